@@ -68,6 +68,9 @@ pub struct EthashParams {
 	pub dao_hardfork_beneficiary: Option<Address>,
 	/// See main EthashParams docs.
 	pub dao_hardfork_accounts: Option<Vec<Address>>,
+	/// Whether the chain took the DAO hard fork. Defaults to true; chains
+	/// which refused it set this to false.
+	pub dao_hardfork_support: Option<bool>,
 
 	/// See main EthashParams docs.
 	pub difficulty_hardfork_transition: Option<Uint>,
@@ -146,6 +149,7 @@ mod tests {
 					"0xbb9bc244d798123fde783fcc1c72d3bb8c189413",
 					"0x807640a13483f8ac783c557fcdf27be11ea4ac7a"
 				],
+				"daoHardforkSupport": false,
 				"difficultyHardforkTransition": "0x59d9",
 				"difficultyHardforkBoundDivisor": "0x0200",
 				"bombDefuseTransition": "0x41",
@@ -191,6 +195,7 @@ mod tests {
 					Address(H160::from("0xbb9bc244d798123fde783fcc1c72d3bb8c189413")),
 					Address(H160::from("0x807640a13483f8ac783c557fcdf27be11ea4ac7a")),
 				]),
+				dao_hardfork_support: Some(false),
 				difficulty_hardfork_transition: Some(Uint(U256::from(0x59d9))),
 				difficulty_hardfork_bound_divisor: Some(Uint(U256::from(0x0200))),
 				bomb_defuse_transition: Some(Uint(U256::from(0x41))),
@@ -230,6 +235,7 @@ mod tests {
 				dao_hardfork_transition: None,
 				dao_hardfork_beneficiary: None,
 				dao_hardfork_accounts: None,
+				dao_hardfork_support: None,
 				difficulty_hardfork_transition: None,
 				difficulty_hardfork_bound_divisor: None,
 				bomb_defuse_transition: None,
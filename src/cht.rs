@@ -0,0 +1,491 @@
+// Copyright 2019 Chainpool
+
+//! Canonical hash trie definitions and helper functions.
+//!
+//! Each CHT is a trie mapping block numbers to canonical hashes and total
+//! difficulty. One is generated for every `SIZE` blocks, allowing us to discard
+//! those blocks in favor of the trie root. When the "ancient" blocks need to be
+//! accessed, we simply request an inclusion proof of a specific block number
+//! against the trie with the root hash. A correct proof implies that the
+//! claimed block is identical to the one we discarded.
+//!
+//! The tries are standard Merkle-Patricia tries keyed by the RLP of the block
+//! number, with the RLP list `[hash, total_difficulty]` as value, so roots are
+//! compatible with the CHT roots found in spec files.
+
+use std::cmp;
+use std::collections::HashMap;
+
+use bytes::Bytes;
+use ethereum_types::{H256, U256};
+use hash::keccak;
+use rlp::{self, Rlp, RlpStream};
+use types::ids::BlockId;
+
+/// The size of each CHT.
+pub const SIZE: u64 = 2048;
+
+/// A canonical hash trie, kept in memory so proofs can be generated from it.
+#[derive(Debug, Clone)]
+pub struct CHT {
+    number: u64,
+    root: H256,
+    /// Trie entries as (key nibbles, value), sorted by key.
+    entries: Vec<(Vec<u8>, Bytes)>,
+}
+
+impl CHT {
+    fn new(number: u64, entries: Vec<(Bytes, Bytes)>) -> Self {
+        let mut entries: Vec<_> = entries
+            .into_iter()
+            .map(|(key, value)| (as_nibbles(&key), value))
+            .collect();
+        entries.sort();
+
+        let mut stream = RlpStream::new();
+        encode_node(&entries, 0, None, &mut Vec::new(), &mut stream);
+
+        CHT {
+            number,
+            root: keccak(stream.out()),
+            entries,
+        }
+    }
+
+    /// Get the root of this CHT.
+    pub fn root(&self) -> H256 {
+        self.root
+    }
+
+    /// Get the number of this CHT.
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    /// Generate an inclusion proof for the entry at a specific block.
+    ///
+    /// The proof consists of every trie node referenced by hash on the path
+    /// from the root to the entry. Returns `None` if the block is not covered
+    /// by this CHT.
+    pub fn prove(&self, num: u64) -> Option<Vec<Bytes>> {
+        if block_to_cht_number(num) != Some(self.number) {
+            return None;
+        }
+
+        let key = as_nibbles(&key(num));
+        let mut proof = Vec::new();
+        let mut stream = RlpStream::new();
+        encode_node(&self.entries, 0, Some(&key), &mut proof, &mut stream);
+
+        // nodes are recorded deepest-first; the root always goes by hash.
+        proof.push(stream.out());
+        proof.reverse();
+        Some(proof)
+    }
+}
+
+/// Block information necessary to construct a CHT.
+pub struct BlockInfo {
+    /// The block's hash.
+    pub hash: H256,
+    /// The block's parent's hash.
+    pub parent_hash: H256,
+    /// The block's total difficulty.
+    pub total_difficulty: U256,
+}
+
+/// Build an in-memory CHT from a closure which provides necessary information
+/// about blocks. If the fetcher ever fails to provide the info, the CHT
+/// will not be generated.
+///
+/// The last block of the range is fetched by number and the rest by walking
+/// parent hashes, so the CHT always describes a single chain.
+pub fn build<F>(cht_num: u64, mut fetcher: F) -> Option<CHT>
+where
+    F: FnMut(BlockId) -> Option<BlockInfo>,
+{
+    let last_num = start_number(cht_num) + SIZE - 1;
+    let mut id = BlockId::Number(last_num);
+    let mut entries = Vec::with_capacity(SIZE as usize);
+
+    for blk_num in (0..SIZE).map(|n| last_num - n) {
+        let info = fetcher(id)?;
+        id = BlockId::Hash(info.parent_hash);
+        entries.push((key(blk_num), value(info.hash, info.total_difficulty)));
+    }
+
+    Some(CHT::new(cht_num, entries))
+}
+
+/// Compute a CHT root from an iterator of (hash, td) pairs, ordered by
+/// ascending block number from the start of the CHT. Fails if the iterator
+/// yields fewer than `SIZE` items; extra items are ignored.
+pub fn compute_root<I>(cht_num: u64, iterable: I) -> Option<H256>
+where
+    I: IntoIterator<Item = (H256, U256)>,
+{
+    let start_num = start_number(cht_num);
+    let entries: Vec<_> = iterable
+        .into_iter()
+        .take(SIZE as usize)
+        .enumerate()
+        .map(|(i, (hash, td))| (key(start_num + i as u64), value(hash, td)))
+        .collect();
+
+    if entries.len() == SIZE as usize {
+        Some(CHT::new(cht_num, entries).root())
+    } else {
+        None
+    }
+}
+
+/// Check a proof for a CHT.
+///
+/// Given a set of trie nodes, a number to query, and a trie root, verify the
+/// given trie branch and extract the canonical hash and total difficulty.
+pub fn check_proof(proof: &[Bytes], num: u64, root: H256) -> Option<(H256, U256)> {
+    let nodes: HashMap<H256, &[u8]> = proof.iter().map(|node| (keccak(node), &node[..])).collect();
+
+    let key = as_nibbles(&key(num));
+    let mut partial = &key[..];
+    let mut node = *nodes.get(&root)?;
+
+    loop {
+        let rlp = Rlp::new(node);
+        match rlp.item_count().ok()? {
+            2 => {
+                let (path, is_leaf) = hex_prefix_decode(rlp.at(0).ok()?.data().ok()?)?;
+                if !partial.starts_with(&path) {
+                    return None;
+                }
+                partial = &partial[path.len()..];
+
+                let next = rlp.at(1).ok()?;
+                if is_leaf {
+                    return if partial.is_empty() {
+                        decode_value(next.data().ok()?)
+                    } else {
+                        None
+                    };
+                }
+                node = resolve(&nodes, &next)?;
+            }
+            17 => {
+                if partial.is_empty() {
+                    return decode_value(rlp.at(16).ok()?.data().ok()?);
+                }
+                let next = rlp.at(partial[0] as usize).ok()?;
+                partial = &partial[1..];
+                node = resolve(&nodes, &next)?;
+            }
+            _ => return None,
+        }
+    }
+}
+
+/// Convert a block number to a CHT number.
+/// Returns `None` for `block_num` == 0, `Some` otherwise.
+pub fn block_to_cht_number(block_num: u64) -> Option<u64> {
+    match block_num {
+        0 => None,
+        n => Some((n - 1) / SIZE),
+    }
+}
+
+/// Get the starting block of a given CHT.
+/// CHT 0 includes block 1...SIZE,
+/// CHT 1 includes block SIZE + 1 ... 2*SIZE
+/// More generally: CHT N includes block (1 + N*SIZE)...((N+1)*SIZE).
+/// This is because the genesis hash is assumed to be known
+/// and including it would be redundant.
+pub fn start_number(cht_num: u64) -> u64 {
+    (cht_num * SIZE) + 1
+}
+
+fn key(num: u64) -> Bytes {
+    rlp::encode(&num).to_vec()
+}
+
+fn value(hash: H256, td: U256) -> Bytes {
+    let mut stream = RlpStream::new_list(2);
+    stream.append(&hash).append(&td);
+    stream.out()
+}
+
+fn decode_value(bytes: &[u8]) -> Option<(H256, U256)> {
+    let rlp = Rlp::new(bytes);
+    Some((rlp.val_at(0).ok()?, rlp.val_at(1).ok()?))
+}
+
+fn as_nibbles(bytes: &[u8]) -> Vec<u8> {
+    bytes.iter().flat_map(|b| vec![b >> 4, b & 0x0f]).collect()
+}
+
+fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
+}
+
+/// Hex-prefix encode a nibble path, flagging whether it ends in a leaf.
+fn hex_prefix_encode(nibbles: &[u8], leaf: bool) -> Bytes {
+    let odd = nibbles.len() % 2 == 1;
+    let mut flag = if leaf { 0x20 } else { 0x00 };
+    let mut rest = nibbles;
+    if odd {
+        flag |= 0x10 | nibbles[0];
+        rest = &nibbles[1..];
+    }
+
+    let mut encoded = Vec::with_capacity(1 + rest.len() / 2);
+    encoded.push(flag);
+    encoded.extend(rest.chunks(2).map(|pair| (pair[0] << 4) | pair[1]));
+    encoded
+}
+
+fn hex_prefix_decode(bytes: &[u8]) -> Option<(Vec<u8>, bool)> {
+    let flag = *bytes.first()?;
+    let is_leaf = flag & 0x20 != 0;
+
+    let mut nibbles = Vec::with_capacity(bytes.len() * 2);
+    if flag & 0x10 != 0 {
+        nibbles.push(flag & 0x0f);
+    }
+    nibbles.extend(as_nibbles(&bytes[1..]));
+    Some((nibbles, is_leaf))
+}
+
+/// Resolve a child reference within a node to the child node's RLP.
+fn resolve<'a>(nodes: &HashMap<H256, &'a [u8]>, child: &Rlp<'a>) -> Option<&'a [u8]> {
+    if child.is_list() {
+        // nodes shorter than a hash are inlined.
+        Some(child.as_raw())
+    } else if child.is_data() && child.size() == 32 {
+        nodes.get(&child.as_val::<H256>().ok()?).cloned()
+    } else {
+        None
+    }
+}
+
+/// Append the trie node for `entries`, whose keys all share the first
+/// `pre_len` nibbles, to `stream`. If `target` is given, nodes referenced by
+/// hash on the path to it are recorded in `proof`.
+fn encode_node(
+    entries: &[(Vec<u8>, Bytes)],
+    pre_len: usize,
+    target: Option<&[u8]>,
+    proof: &mut Vec<Bytes>,
+    stream: &mut RlpStream,
+) {
+    if entries.is_empty() {
+        stream.append_empty_data();
+        return;
+    }
+
+    let key = &entries[0].0;
+    if entries.len() == 1 {
+        stream.begin_list(2);
+        stream.append(&hex_prefix_encode(&key[pre_len..], true));
+        stream.append(&entries[0].1);
+        return;
+    }
+
+    let shared = entries.iter().skip(1).fold(key.len(), |acc, (k, _)| {
+        cmp::min(shared_prefix_len(key, k), acc)
+    });
+    if shared > pre_len {
+        stream.begin_list(2);
+        stream.append(&hex_prefix_encode(&key[pre_len..shared], false));
+        encode_child(entries, shared, target, proof, stream);
+        return;
+    }
+
+    stream.begin_list(17);
+    let value = if key.len() == pre_len {
+        Some(&entries[0].1)
+    } else {
+        None
+    };
+    let mut begin = if value.is_some() { 1 } else { 0 };
+    for nibble in 0..16u8 {
+        let len = entries[begin..]
+            .iter()
+            .take_while(|(k, _)| k[pre_len] == nibble)
+            .count();
+        if len == 0 {
+            stream.append_empty_data();
+        } else {
+            encode_child(
+                &entries[begin..begin + len],
+                pre_len + 1,
+                target,
+                proof,
+                stream,
+            );
+        }
+        begin += len;
+    }
+    match value {
+        Some(value) => stream.append(value),
+        None => stream.append_empty_data(),
+    };
+}
+
+/// Append a reference to the child node for `entries`: the node itself if it
+/// is shorter than a hash, its hash otherwise.
+fn encode_child(
+    entries: &[(Vec<u8>, Bytes)],
+    pre_len: usize,
+    target: Option<&[u8]>,
+    proof: &mut Vec<Bytes>,
+    stream: &mut RlpStream,
+) {
+    // only follow the target into the subtrie which contains it.
+    let target = target.filter(|target| entries.iter().any(|(k, _)| &k[..] == *target));
+
+    let mut child = RlpStream::new();
+    encode_node(entries, pre_len, target, proof, &mut child);
+    let child = child.out();
+
+    if child.len() < 32 {
+        stream.append_raw(&child, 1);
+    } else {
+        stream.append(&keccak(&child));
+        if target.is_some() {
+            proof.push(child);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlockInfo, CHT, SIZE};
+    use ethereum_types::{H256, U256};
+    use hash::{keccak, KECCAK_NULL_RLP};
+    use rustc_hex::FromHex;
+    use std::collections::HashMap;
+    use types::ids::BlockId;
+
+    fn block_hash(num: u64) -> H256 {
+        keccak(num.to_string())
+    }
+
+    fn fetcher() -> impl FnMut(BlockId) -> Option<BlockInfo> {
+        let numbers: HashMap<H256, u64> = (0..3 * SIZE).map(|n| (block_hash(n), n)).collect();
+        move |id| {
+            let num = match id {
+                BlockId::Number(num) => num,
+                BlockId::Hash(hash) => *numbers.get(&hash)?,
+                _ => return None,
+            };
+
+            Some(BlockInfo {
+                hash: block_hash(num),
+                parent_hash: block_hash(num - 1),
+                total_difficulty: U256::from(num * 10),
+            })
+        }
+    }
+
+    #[test]
+    fn size_equals_start_number() {
+        assert_eq!(super::start_number(0), 1);
+        assert_eq!(super::start_number(1), SIZE + 1);
+        assert_eq!(super::block_to_cht_number(0), None);
+        assert_eq!(super::block_to_cht_number(1), Some(0));
+        assert_eq!(super::block_to_cht_number(SIZE), Some(0));
+        assert_eq!(super::block_to_cht_number(SIZE + 1), Some(1));
+    }
+
+    #[test]
+    fn build_and_check_proof() {
+        let cht = super::build(1, fetcher()).unwrap();
+        assert_eq!(cht.number(), 1);
+
+        for num in [SIZE + 1, SIZE + 2, SIZE + 300, 2 * SIZE] {
+            let proof = cht.prove(num).unwrap();
+            assert_eq!(
+                super::check_proof(&proof, num, cht.root()),
+                Some((block_hash(num), U256::from(num * 10)))
+            );
+        }
+
+        assert!(cht.prove(SIZE).is_none());
+        assert!(cht.prove(2 * SIZE + 1).is_none());
+    }
+
+    #[test]
+    fn compute_root_matches_build() {
+        let cht = super::build(0, fetcher()).unwrap();
+        let root = super::compute_root(
+            0,
+            (1..SIZE + 1).map(|num| (block_hash(num), U256::from(num * 10))),
+        );
+        assert_eq!(root, Some(cht.root()));
+
+        assert_eq!(
+            super::compute_root(0, (1..SIZE).map(|num| (block_hash(num), U256::from(num)))),
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_bad_proofs() {
+        let cht = super::build(0, fetcher()).unwrap();
+        let proof = cht.prove(100).unwrap();
+
+        // wrong root, wrong number and a tampered node must all fail.
+        assert!(super::check_proof(&proof, 100, H256::zero()).is_none());
+        assert!(super::check_proof(&proof, 101, cht.root()).is_none());
+
+        let mut tampered = proof.clone();
+        let last = tampered.len() - 1;
+        let node_len = tampered[last].len();
+        tampered[last][node_len - 1] ^= 1;
+        assert!(super::check_proof(&tampered, 100, cht.root()).is_none());
+
+        assert!(super::check_proof(&[], 100, cht.root()).is_none());
+    }
+
+    #[test]
+    fn matches_reference_trie_roots() {
+        // roots from the ethereum/tests `trieanyorder` vectors.
+        let root = |pairs: &[(&str, &str)]| {
+            let entries = pairs
+                .iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value.as_bytes().to_vec()))
+                .collect();
+            CHT::new(0, entries).root()
+        };
+        let expected = |hex: &str| H256::from_slice(&hex.from_hex().unwrap());
+
+        assert_eq!(root(&[]), KECCAK_NULL_RLP);
+        assert_eq!(
+            root(&[("foo", "bar"), ("food", "bass")]),
+            expected("17beaa1648bafa633cda809c90c04af50fc8aed3cb40d16efbddee6fdf63c4c3")
+        );
+        assert_eq!(
+            root(&[("be", "e"), ("dog", "puppy"), ("bed", "d")]),
+            expected("3f67c7a47520f79faa29255d2d3c084a7a6df0453116ed7232ff10277a8be68b")
+        );
+        assert_eq!(
+            root(&[("test", "test"), ("te", "testy")]),
+            expected("8452568af70d8d140f58d941338542f645fcca50094b20f3c3d8c3df49337928")
+        );
+        assert_eq!(
+            root(&[
+                ("doe", "reindeer"),
+                ("dog", "puppy"),
+                ("dogglesworth", "cat")
+            ]),
+            expected("8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3")
+        );
+        assert_eq!(
+            root(&[
+                ("do", "verb"),
+                ("dog", "puppy"),
+                ("doge", "coin"),
+                ("horse", "stallion")
+            ]),
+            expected("5991bb8c6514148a29db676a14ac506cd2cd5775ace63c30a4fe457715e9ac84")
+        );
+    }
+}
@@ -0,0 +1,317 @@
+// Copyright 2019 Chainpool
+
+//! Difficulty test vectors run against `Ethash::calculate_difficulty`.
+//!
+//! The fixture tests read the `BasicTests/difficulty*.json` files of the
+//! [ethereum/tests](https://github.com/ethereum/tests) repository from
+//! `res/ethereum/tests`, or from the checkout `ETHEREUM_TESTS` points at. The
+//! files are not vendored yet (see `res/ethereum/tests/README.md`), so the
+//! fixture tests are ignored until they are:
+//!
+//! ```text
+//! ETHEREUM_TESTS=/path/to/tests cargo test difficulty_tests -- --ignored
+//! ```
+//!
+//! A handful of hand-computed vectors for every preset always run, so the
+//! presets and the harness itself are covered without the fixtures.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::path::PathBuf;
+
+use crate::ethash_wrapper::{CommonParams, Ethash, EthashParams};
+use crate::header::{BlockNumber, Header};
+use ethereum_types::U256;
+use ethjson::test::DifficultyTest;
+use tempdir::TempDir;
+
+fn common_params() -> CommonParams {
+    CommonParams {
+        maximum_extra_data_size: 32,
+        min_gas_limit: 5000.into(),
+        gas_limit_bound_divisor: 1024.into(),
+    }
+}
+
+/// Mainnet parameters with every fork from Homestead on disabled.
+fn frontier() -> EthashParams {
+    EthashParams {
+        minimum_difficulty: 131_072.into(),
+        difficulty_bound_divisor: 2048.into(),
+        difficulty_increment_divisor: 10,
+        metropolis_difficulty_increment_divisor: 9,
+        duration_limit: 13,
+        homestead_transition: u64::MAX,
+        difficulty_hardfork_transition: u64::MAX,
+        difficulty_hardfork_bound_divisor: 2048.into(),
+        bomb_defuse_transition: u64::MAX,
+        eip100b_transition: u64::MAX,
+        ecip1010_pause_transition: u64::MAX,
+        ecip1010_continue_transition: u64::MAX,
+        ecip1017_era_rounds: u64::MAX,
+        block_reward: BTreeMap::new(),
+        expip2_transition: u64::MAX,
+        expip2_duration_limit: 30,
+        block_reward_contract_transition: 0,
+        difficulty_bomb_delays: BTreeMap::new(),
+        dao_hardfork_transition: u64::MAX,
+        dao_hardfork_support: true,
+    }
+}
+
+fn homestead() -> EthashParams {
+    EthashParams {
+        homestead_transition: 0,
+        ..frontier()
+    }
+}
+
+/// Homestead with EIP-100 and the difficulty bomb delayed by `bomb_delay`
+/// blocks, as from Byzantium on.
+fn metropolis(bomb_delay: BlockNumber) -> EthashParams {
+    let mut difficulty_bomb_delays = BTreeMap::new();
+    difficulty_bomb_delays.insert(0, bomb_delay);
+    EthashParams {
+        eip100b_transition: 0,
+        difficulty_bomb_delays,
+        ..homestead()
+    }
+}
+
+fn byzantium() -> EthashParams {
+    metropolis(3_000_000)
+}
+
+fn constantinople() -> EthashParams {
+    metropolis(5_000_000)
+}
+
+fn muir_glacier() -> EthashParams {
+    metropolis(9_000_000)
+}
+
+/// Run every case in `test`, panicking with all mismatches at once.
+/// Returns the number of cases run.
+fn check(params: EthashParams, test: DifficultyTest) -> usize {
+    let tempdir = TempDir::new("difficulty").unwrap();
+    let engine = Ethash::new(tempdir.path(), common_params(), params, None);
+
+    let mut cases = 0;
+    let mut failures = Vec::new();
+    for (name, case) in test {
+        cases += 1;
+        let number: BlockNumber = case.current_block_number.into();
+        let parent_number = match number.checked_sub(1) {
+            Some(parent_number) => parent_number,
+            None => {
+                failures.push(format!("{}: the genesis block has no parent", name));
+                continue;
+            }
+        };
+
+        let mut parent = Header::new();
+        parent.set_number(parent_number);
+        parent.set_gas_limit(0x20000.into());
+        parent.set_timestamp(case.parent_timestamp.into());
+        parent.set_difficulty(case.parent_difficulty.into());
+        parent.set_uncles_hash(case.parent_uncles.into());
+
+        let mut header = Header::new();
+        header.set_number(number);
+        header.set_timestamp(case.current_timestamp.into());
+
+        let expected: U256 = case.current_difficulty.into();
+        match engine.calculate_difficulty(&header, &parent) {
+            Ok(found) if found == expected => {}
+            Ok(found) => failures.push(format!("{}: expected {}, found {}", name, expected, found)),
+            Err(e) => failures.push(format!("{}: expected {}, failed: {}", name, expected, e)),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "{} of {} cases failed:\n{}",
+        failures.len(),
+        cases,
+        failures.join("\n")
+    );
+    cases
+}
+
+fn check_json(params: EthashParams, json: &str) {
+    check(params, DifficultyTest::load(json.as_bytes()).unwrap());
+}
+
+fn check_fixture(params: EthashParams, file: &str) {
+    let dir = env::var_os("ETHEREUM_TESTS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("res/ethereum/tests"));
+    let path = dir.join("BasicTests").join(file);
+    let reader = File::open(&path)
+        .unwrap_or_else(|e| panic!("can't open {}: {}; see the module docs", path.display(), e));
+    let test = DifficultyTest::load(reader)
+        .unwrap_or_else(|e| panic!("can't parse {}: {}", path.display(), e));
+    assert!(check(params, test) > 0, "{} has no cases", path.display());
+}
+
+#[test]
+fn frontier_vectors() {
+    check_json(
+        frontier(),
+        r#"{
+            "faster": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x01", "currentBlockNumber": "0x01",
+                "currentDifficulty": "0x020040"
+            },
+            "slowerClampedToMinimum": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x0d", "currentBlockNumber": "0x01",
+                "currentDifficulty": "0x020000"
+            },
+            "firstBomb": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x100000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x01", "currentBlockNumber": "0x030d40",
+                "currentDifficulty": "0x100201"
+            }
+        }"#,
+    );
+}
+
+#[test]
+fn homestead_vectors() {
+    check_json(
+        homestead(),
+        r#"{
+            "faster": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x01", "currentBlockNumber": "0x0f4240",
+                "currentDifficulty": "0x020140"
+            },
+            "slower": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x19", "currentBlockNumber": "0x0f4240",
+                "currentDifficulty": "0x020100"
+            }
+        }"#,
+    );
+}
+
+#[test]
+fn byzantium_vectors() {
+    check_json(
+        byzantium(),
+        r#"{
+            "noUncles": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x09", "currentBlockNumber": "0x3d0900",
+                "currentDifficulty": "0x020100"
+            },
+            "parentHasUncles": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x0000000000000000000000000000000000000000000000000000000000000001",
+                "currentTimestamp": "0x09", "currentBlockNumber": "0x3d0900",
+                "currentDifficulty": "0x020140"
+            }
+        }"#,
+    );
+}
+
+#[test]
+fn constantinople_vectors() {
+    check_json(
+        constantinople(),
+        r#"{
+            "delayedBomb": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x09", "currentBlockNumber": "0x5b8d80",
+                "currentDifficulty": "0x020100"
+            }
+        }"#,
+    );
+}
+
+#[test]
+fn muir_glacier_vectors() {
+    check_json(
+        muir_glacier(),
+        r#"{
+            "delayedBomb": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x09", "currentBlockNumber": "0x989680",
+                "currentDifficulty": "0x020100"
+            }
+        }"#,
+    );
+}
+
+#[test]
+#[should_panic(expected = "1 of 1 cases failed")]
+fn reports_mismatches() {
+    check_json(
+        homestead(),
+        r#"{
+            "wrong": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x01", "currentBlockNumber": "0x01",
+                "currentDifficulty": "0x020000"
+            }
+        }"#,
+    );
+}
+
+#[test]
+#[should_panic(expected = "the genesis block has no parent")]
+fn reports_genesis_cases() {
+    check_json(
+        homestead(),
+        r#"{
+            "genesis": {
+                "parentTimestamp": "0x00", "parentDifficulty": "0x020000",
+                "parentUncles": "0x1dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347",
+                "currentTimestamp": "0x01", "currentBlockNumber": "0x00",
+                "currentDifficulty": "0x020000"
+            }
+        }"#,
+    );
+}
+
+#[test]
+#[ignore = "needs the ethereum/tests difficulty fixtures"]
+fn frontier_fixtures() {
+    check_fixture(frontier(), "difficultyFrontier.json");
+}
+
+#[test]
+#[ignore = "needs the ethereum/tests difficulty fixtures"]
+fn homestead_fixtures() {
+    check_fixture(homestead(), "difficultyHomestead.json");
+}
+
+#[test]
+#[ignore = "needs the ethereum/tests difficulty fixtures"]
+fn byzantium_fixtures() {
+    check_fixture(byzantium(), "difficultyByzantium.json");
+}
+
+#[test]
+#[ignore = "needs the ethereum/tests difficulty fixtures"]
+fn constantinople_fixtures() {
+    check_fixture(constantinople(), "difficultyConstantinople.json");
+}
+
+#[test]
+#[ignore = "needs the ethereum/tests difficulty fixtures"]
+fn muir_glacier_fixtures() {
+    check_fixture(muir_glacier(), "difficultyEIP2384.json");
+}
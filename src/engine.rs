@@ -0,0 +1,147 @@
+// Copyright 2019 Chainpool
+
+//! Consensus engines verifying headers.
+//!
+//! Verification is split into stages of increasing cost and context, as in
+//! parity. The basic and unordered stages look at a header on its own and
+//! are run by a `VerificationQueue`; the family stage needs the parent and
+//! is run by the `HeaderChain` when the header is inserted.
+
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::header::{BlockNumber, Header};
+use crate::verification_queue::Verifier;
+
+/// A consensus engine.
+///
+/// Every stage accepts any header by default, and blocks may not include
+/// uncles.
+pub trait Engine: Send + Sync {
+    /// Maximum number of uncles a block may include.
+    fn maximum_uncle_count(&self, _block: BlockNumber) -> usize {
+        0
+    }
+
+    /// Phase 1 quick header verification. Only does checks that are cheap,
+    /// such as the seal format and a quick proof-of-work check.
+    fn verify_block_basic(&self, _header: &Header) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Phase 2 verification. Performs costly checks which do not depend on
+    /// other headers, such as the full Ethash seal check.
+    fn verify_block_unordered(&self, _header: &Header) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Phase 3 verification. Checks the header against its parent.
+    fn verify_block_family(&self, _header: &Header, _parent: &Header) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Whether the header is final by the engine's own rules, e.g. because
+    /// it carries enough validator signatures. Such headers win the
+    /// `ProofOfAuthority` fork choice and are never reorganized away once
+    /// canonical. Nothing is final by default.
+    fn is_finalized(&self, _header: &Header) -> bool {
+        false
+    }
+
+    /// Run every verification stage in order.
+    fn verify_block_full(&self, header: &Header, parent: &Header) -> Result<(), Error> {
+        self.verify_block_basic(header)?;
+        self.verify_block_unordered(header)?;
+        self.verify_block_family(header, parent)
+    }
+}
+
+/// Engine which accepts every header.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct NullEngine;
+
+impl Engine for NullEngine {
+    fn maximum_uncle_count(&self, _block: BlockNumber) -> usize {
+        2
+    }
+}
+
+/// Runs the stages of an engine which need no parent, for use with a
+/// `VerificationQueue`.
+pub struct EngineVerifier<E>(pub Arc<E>);
+
+impl<E: Engine> Verifier for EngineVerifier<E> {
+    fn verify(&self, header: &Header) -> Result<(), Error> {
+        self.0.verify_block_basic(header)?;
+        self.0.verify_block_unordered(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Engine, EngineVerifier};
+    use crate::error::{BlockError, Error, ErrorKind};
+    use crate::header::Header;
+    use crate::verification_queue::Verifier;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts the stages run and rejects headers without extra data in the
+    /// unordered stage.
+    #[derive(Default)]
+    struct CountingEngine {
+        basic: AtomicUsize,
+        unordered: AtomicUsize,
+        family: AtomicUsize,
+    }
+
+    impl Engine for CountingEngine {
+        fn verify_block_basic(&self, _header: &Header) -> Result<(), Error> {
+            self.basic.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn verify_block_unordered(&self, header: &Header) -> Result<(), Error> {
+            self.unordered.fetch_add(1, Ordering::SeqCst);
+            if header.extra_data().is_empty() {
+                return Err(ErrorKind::PowInvalid.into());
+            }
+            Ok(())
+        }
+
+        fn verify_block_family(&self, _header: &Header, _parent: &Header) -> Result<(), Error> {
+            self.family.fetch_add(1, Ordering::SeqCst);
+            Err(BlockError::InvalidSeal.into())
+        }
+    }
+
+    #[test]
+    fn full_verification_runs_every_stage() {
+        let engine = CountingEngine::default();
+        let mut header = Header::new();
+        header.set_extra_data(vec![1]);
+
+        match engine.verify_block_full(&header, &Header::new()) {
+            Err(Error(ErrorKind::Block(BlockError::InvalidSeal), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(engine.basic.load(Ordering::SeqCst), 1);
+        assert_eq!(engine.unordered.load(Ordering::SeqCst), 1);
+        assert_eq!(engine.family.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn verifier_skips_family_stage() {
+        let engine = Arc::new(CountingEngine::default());
+        let verifier = EngineVerifier(engine.clone());
+
+        let mut header = Header::new();
+        assert!(verifier.verify(&header).is_err());
+        header.set_extra_data(vec![1]);
+        assert!(verifier.verify(&header).is_ok());
+
+        assert_eq!(engine.basic.load(Ordering::SeqCst), 2);
+        assert_eq!(engine.unordered.load(Ordering::SeqCst), 2);
+        assert_eq!(engine.family.load(Ordering::SeqCst), 0);
+    }
+}
@@ -16,22 +16,31 @@
 
 //! General error types for use in ethcore.
 
-use std::{fmt, error};
+use std::{fmt, error, io};
 use std::time::SystemTime;
 use ethereum_types::{H256, U256, Address, Bloom};
 use unexpected::{Mismatch, OutOfBounds};
 //use ethtrie::TrieError;
 use crate::header::BlockNumber;
+use bytes::{Bytes, ToPretty};
 use ethkey::Error as EthkeyError;
 use rlp;
 
-#[derive(Debug, PartialEq, Clone, Copy, Eq)]
+#[derive(Debug, PartialEq, Clone, Eq)]
 /// Errors concerning block processing.
 pub enum BlockError {
 	/// Block has too many uncles.
 	TooManyUncles(OutOfBounds<usize>),
 	/// Extra data is of an invalid length.
 	ExtraDataOutOfBounds(OutOfBounds<usize>),
+	/// Extra data of a block right after the DAO hard fork doesn't match the side
+	/// of the fork the chain took.
+	InvalidDaoExtraData {
+		/// Whether the chain took the fork and so requires the DAO extra data.
+		required: bool,
+		/// Extra data found in the header.
+		found: Bytes,
+	},
 	/// Seal is incorrect format.
 	InvalidSealArity(Mismatch<usize>),
 	/// Block has too much gas used.
@@ -60,6 +69,8 @@ pub enum BlockError {
 	/// Difficulty header field is invalid; this is a strong error used after getting a definitive
 	/// value for difficulty (which is provided).
 	InvalidDifficulty(Mismatch<U256>),
+	/// Difficulty calculated for the header doesn't fit in 256 bits.
+	DifficultyOverflow,
 	/// Seal element of type H256 (max_hash for Ethash, but could be something else for
 	/// other seal engines) is out of bounds.
 	MismatchedH256SealElement(Mismatch<H256>),
@@ -75,6 +86,8 @@ pub enum BlockError {
 	InvalidTimestamp(OutOfBounds<SystemTime>),
 	/// Timestamp header field is too far in future.
 	TemporarilyInvalid(OutOfBounds<SystemTime>),
+	/// Timestamp header overflowed.
+	TimestampOverflow,
 	/// Log bloom header field is invalid.
 	InvalidLogBloom(Mismatch<Bloom>),
 	/// Number field of header is invalid.
@@ -98,6 +111,10 @@ impl fmt::Display for BlockError {
 		let msg = match *self {
 			TooManyUncles(ref oob) => format!("Block has too many uncles. {}", oob),
 			ExtraDataOutOfBounds(ref oob) => format!("Extra block data too long. {}", oob),
+			InvalidDaoExtraData { required: true, ref found } =>
+				format!("Invalid DAO hard fork extra data. Expected \"dao-hard-fork\", found {}", found.pretty()),
+			InvalidDaoExtraData { required: false, ref found } =>
+				format!("Invalid DAO hard fork extra data. Expected anything but \"dao-hard-fork\", found {}", found.pretty()),
 			InvalidSealArity(ref mis) => format!("Block seal in incorrect format: {}", mis),
 			TooMuchGasUsed(ref oob) => format!("Block has too much gas used. {}", oob),
 			InvalidUnclesHash(ref mis) => format!("Block has invalid uncles hash: {}", mis),
@@ -111,6 +128,7 @@ impl fmt::Display for BlockError {
 			InvalidTransactionsRoot(ref mis) => format!("Invalid transactions root in header: {}", mis),
 			DifficultyOutOfBounds(ref oob) => format!("Invalid block difficulty: {}", oob),
 			InvalidDifficulty(ref mis) => format!("Invalid block difficulty: {}", mis),
+			DifficultyOverflow => "Difficulty overflow".into(),
 			MismatchedH256SealElement(ref mis) => format!("Seal element out of bounds: {}", mis),
 			InvalidProofOfWork(ref oob) => format!("Block has invalid PoW: {}", oob),
 			InvalidSeal => "Block has invalid seal.".into(),
@@ -124,6 +142,7 @@ impl fmt::Display for BlockError {
 				let oob = oob.map(|st| st.elapsed().unwrap_or_default().as_secs());
 				format!("Future timestamp in header: {}", oob)
 			},
+			TimestampOverflow => "Timestamp overflow".into(),
 			InvalidLogBloom(ref oob) => format!("Invalid log bloom in header: {}", oob),
 			InvalidNumber(ref mis) => format!("Invalid number in header: {}", mis),
 			RidiculousNumber(ref oob) => format!("Implausible block number. {}", oob),
@@ -153,6 +172,7 @@ error_chain! {
 		Block(BlockError) #[doc = "Error concerning block processing."];
 		Ethkey(EthkeyError) #[doc = "Ethkey error."];
 		Decoder(rlp::DecoderError) #[doc = "RLP decoding errors"];
+		Io(io::Error) #[doc = "Io error."];
 	}
 
 	errors {
@@ -167,5 +187,53 @@ error_chain! {
 			description("The value of the nonce or mishash is invalid.")
 			display("The value of the nonce or mishash is invalid.")
 		}
+
+		#[doc = "Header is already in the verification queue."]
+		AlreadyQueued {
+			description("Header is already in the verification queue.")
+			display("Header is already in the verification queue.")
+		}
+
+		#[doc = "A heavier fork would reorganize more blocks than allowed."]
+		ReorgTooDeep(depth: u64, max: u64) {
+			description("Reorganization is deeper than allowed.")
+			display("Reorganization of {} blocks exceeds the maximum depth of {}.", depth, max)
+		}
+
+		#[doc = "Block is not held in full by the header chain."]
+		UnavailableBlock(number: BlockNumber) {
+			description("Block is not available.")
+			display("Block {} is not available.", number)
+		}
+
+		#[doc = "Block is not part of the canonical chain."]
+		NotCanonical(hash: H256) {
+			description("Block is not part of the canonical chain.")
+			display("Block {} is not part of the canonical chain.", hash)
+		}
+
+		#[doc = "Reverting would go below the finalized or checkpoint block."]
+		RevertBelowBoundary(number: BlockNumber, boundary: BlockNumber) {
+			description("Cannot revert below the finalized or checkpoint block.")
+			display("Cannot revert to block {} below block {}.", number, boundary)
+		}
+
+		#[doc = "Verification queue is full."]
+		QueueFull(queued: usize) {
+			description("Verification queue is full.")
+			display("Verification queue is full with {} items.", queued)
+		}
+
+		#[doc = "Verification queue was configured without verifier threads."]
+		NoVerifiers {
+			description("Verification queue needs at least one verifier thread.")
+			display("Verification queue needs at least one verifier thread.")
+		}
+
+		#[doc = "The verifier panicked while checking a header."]
+		VerifierPanicked {
+			description("Verifier panicked.")
+			display("Verifier panicked while checking the header.")
+		}
 	}
 }
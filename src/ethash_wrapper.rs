@@ -18,12 +18,15 @@ use std::path::Path;
 use std::cmp;
 use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use hash::{KECCAK_EMPTY_LIST_RLP};
-use ethash::{self, quick_get_difficulty, slow_hash_block_number, EthashManager, OptimizeFor};
+use ethash::{self, quick_get_difficulty, slow_hash_block_number, EthashManager, OptimizeFor, ETHASH_EPOCH_LENGTH};
 use ethereum_types::{H256, H64, U256};
 use unexpected::{OutOfBounds, Mismatch};
 use crate::error::{BlockError, Error};
 use crate::header::{Header, BlockNumber, ExtendedHeader};
+use crate::clock::{Clock, SystemClock};
+use crate::engine::Engine;
 use ethjson;
 use rlp::Rlp;
 
@@ -32,6 +35,18 @@ use rlp::Rlp;
 const SNAPSHOT_BLOCKS: u64 = 5000;
 /// Maximum number of blocks allowed in an ethash snapshot.
 const MAX_SNAPSHOT_BLOCKS: u64 = 30000;
+/// Extra data carried by the blocks right after the DAO hard fork.
+const DAO_EXTRA_DATA: &[u8] = b"dao-hard-fork";
+/// Number of blocks from the DAO hard fork which must carry `DAO_EXTRA_DATA`.
+const DAO_EXTRA_DATA_BLOCKS: u64 = 10;
+/// How far a header's timestamp may be ahead of the local clock.
+const ACCEPTABLE_DRIFT: Duration = Duration::from_secs(15);
+/// Highest block number we build an ethash cache for. A forged header far in
+/// the future would otherwise make us generate a cache for its epoch, which
+/// grows with every epoch, and push the epoch size arithmetic towards
+/// overflow. Epoch 2048 starts at block 61,440,000, years ahead of any
+/// ethash chain.
+const MAX_BLOCK_NUMBER: BlockNumber = 2048 * ETHASH_EPOCH_LENGTH - 1;
 
 /// Ethash specific seal
 #[derive(Debug, PartialEq)]
@@ -65,6 +80,27 @@ impl Seal {
 	}
 }
 
+/// Parameters common to all engines.
+#[derive(Debug, PartialEq)]
+pub struct CommonParams {
+	/// Maximum size of extra data.
+	pub maximum_extra_data_size: usize,
+	/// Minimum gas limit.
+	pub min_gas_limit: U256,
+	/// Gas limit bound divisor (how much gas limit can change per block).
+	pub gas_limit_bound_divisor: U256,
+}
+
+impl From<ethjson::spec::Params> for CommonParams {
+	fn from(p: ethjson::spec::Params) -> Self {
+		CommonParams {
+			maximum_extra_data_size: p.maximum_extra_data_size.into(),
+			min_gas_limit: p.min_gas_limit.into(),
+			gas_limit_bound_divisor: p.gas_limit_bound_divisor.into(),
+		}
+	}
+}
+
 /// Ethash params.
 #[derive(Debug, PartialEq)]
 pub struct EthashParams {
@@ -106,6 +142,12 @@ pub struct EthashParams {
 	//pub block_reward_contract: Option<BlockRewardContract>,
 	/// Difficulty bomb delays.
 	pub difficulty_bomb_delays: BTreeMap<BlockNumber, BlockNumber>,
+	/// DAO hard fork transition block.
+	pub dao_hardfork_transition: u64,
+	/// Whether the chain took the DAO hard fork. Chains which refused it,
+	/// such as Ethereum Classic, set the transition and clear this flag so
+	/// that headers carrying the DAO extra data are rejected.
+	pub dao_hardfork_support: bool,
 }
 
 impl From<ethjson::spec::EthashParams> for EthashParams {
@@ -154,32 +196,115 @@ impl From<ethjson::spec::EthashParams> for EthashParams {
 			},*/
 			difficulty_bomb_delays: p.difficulty_bomb_delays.unwrap_or_default().into_iter()
 				.map(|(block, delay)| (block.into(), delay.into()))
-				.collect()
+				.collect(),
+			dao_hardfork_transition: p.dao_hardfork_transition.map_or(u64::max_value(), Into::into),
+			dao_hardfork_support: p.dao_hardfork_support.unwrap_or(true),
 		}
 	}
 }
 
+/// Rewards bestowed by a block.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockRewards {
+	/// Reward of the block's author, including the bonus for uncles.
+	pub author: U256,
+	/// Reward of each uncle's author, in the order the uncles were given.
+	pub uncles: Vec<U256>,
+}
+
+impl BlockRewards {
+	/// Sum of all rewards, i.e. the ether issued by the block.
+	pub fn total(&self) -> U256 {
+		self.uncles.iter().fold(self.author, |total, reward| total + *reward)
+	}
+}
+
 /// Engine using Ethash proof-of-work consensus algorithm, suitable for Ethereum
 /// mainnet chains in the Olympic, Frontier and Homestead eras.
 pub struct Ethash {
+	params: CommonParams,
 	ethash_params: EthashParams,
 	pow: EthashManager,
+	clock: Arc<dyn Clock>,
 }
 
 impl Ethash {
 	/// Create a new instance of Ethash engine
 	pub fn new<T: Into<Option<OptimizeFor>>>(
 		cache_dir: &Path,
+		params: CommonParams,
+		ethash_params: EthashParams,
+		optimize_for: T,
+	) -> Arc<Self> {
+		Self::with_clock(cache_dir, params, ethash_params, optimize_for, Arc::new(SystemClock))
+	}
+
+	/// Create a new instance of Ethash engine which reads the time from `clock`.
+	pub fn with_clock<T: Into<Option<OptimizeFor>>>(
+		cache_dir: &Path,
+		params: CommonParams,
 		ethash_params: EthashParams,
 		optimize_for: T,
+		clock: Arc<dyn Clock>,
 	) -> Arc<Self> {
 		Arc::new(Ethash {
+			params,
 			ethash_params,
 			pow: EthashManager::new(cache_dir.as_ref(), optimize_for.into()),
+			clock,
 		})
 	}
 
+	/// Check that the blocks right after the DAO hard fork carry the DAO extra
+	/// data if the chain took the fork, and that they don't if it refused it.
+	fn verify_dao_extra_data(&self, header: &Header) -> Result<(), Error> {
+		let transition = self.ethash_params.dao_hardfork_transition;
+		let in_window = header.number() >= transition &&
+			header.number() < transition.saturating_add(DAO_EXTRA_DATA_BLOCKS);
+		if !in_window {
+			return Ok(());
+		}
+
+		let required = self.ethash_params.dao_hardfork_support;
+		let is_dao_extra_data = header.extra_data()[..] == DAO_EXTRA_DATA[..];
+		if is_dao_extra_data != required {
+			return Err(From::from(BlockError::InvalidDaoExtraData { required, found: header.extra_data().clone() }));
+		}
+		Ok(())
+	}
+}
+
+impl Engine for Ethash {
+	fn maximum_uncle_count(&self, _block: BlockNumber) -> usize { 2 }
+
 	fn verify_block_basic(&self, header: &Header) -> Result<(), Error> {
+		verify_block_number(header)?;
+		if header.gas_used() > header.gas_limit() {
+			return Err(From::from(BlockError::TooMuchGasUsed(OutOfBounds { max: Some(*header.gas_limit()), min: None, found: *header.gas_used() })));
+		}
+		let min_gas_limit = self.params.min_gas_limit;
+		if header.gas_limit() < &min_gas_limit {
+			return Err(From::from(BlockError::InvalidGasLimit(OutOfBounds { min: Some(min_gas_limit), max: None, found: *header.gas_limit() })));
+		}
+
+		let maximum_extra_data_size = self.params.maximum_extra_data_size;
+		if header.extra_data().len() > maximum_extra_data_size {
+			return Err(From::from(BlockError::ExtraDataOutOfBounds(OutOfBounds { min: None, max: Some(maximum_extra_data_size), found: header.extra_data().len() })));
+		}
+		self.verify_dao_extra_data(header)?;
+
+		// headers slightly ahead of the clock may become valid soon, those
+		// far ahead never will.
+		let max_time = self.clock.now() + ACCEPTABLE_DRIFT;
+		let invalid_threshold = max_time + ACCEPTABLE_DRIFT * 9;
+		let timestamp = timestamp_to_time(header.timestamp())?;
+		if timestamp > invalid_threshold {
+			return Err(From::from(BlockError::InvalidTimestamp(OutOfBounds { max: Some(max_time), min: None, found: timestamp })));
+		}
+		if timestamp > max_time {
+			return Err(From::from(BlockError::TemporarilyInvalid(OutOfBounds { max: Some(max_time), min: None, found: timestamp })));
+		}
+
 		// check the seal fields.
 		let seal = Seal::parse_seal(header.seal())?;
 
@@ -203,6 +328,7 @@ impl Ethash {
 	}
 
 	fn verify_block_unordered(&self, header: &Header) -> Result<(), Error> {
+		verify_block_number(header)?;
 		let seal = Seal::parse_seal(header.seal())?;
 
 		let result = self.pow.compute_light(header.number() as u64, &header.bare_hash().0, seal.nonce.low_u64());
@@ -231,8 +357,22 @@ impl Ethash {
 			return Err(From::from(BlockError::RidiculousNumber(OutOfBounds { min: Some(1), max: None, found: header.number() })));
 		}
 
+		if header.timestamp() <= parent.timestamp() {
+			let min = timestamp_to_time(parent.timestamp().saturating_add(1))?;
+			let found = timestamp_to_time(header.timestamp())?;
+			return Err(From::from(BlockError::InvalidTimestamp(OutOfBounds { max: None, min: Some(min), found })));
+		}
+
+		let gas_limit_divisor = self.params.gas_limit_bound_divisor;
+		let parent_gas_limit = *parent.gas_limit();
+		let min_gas = parent_gas_limit - parent_gas_limit / gas_limit_divisor;
+		let max_gas = parent_gas_limit.saturating_add(parent_gas_limit / gas_limit_divisor);
+		if header.gas_limit() <= &min_gas || header.gas_limit() >= &max_gas {
+			return Err(From::from(BlockError::InvalidGasLimit(OutOfBounds { min: Some(min_gas), max: Some(max_gas), found: *header.gas_limit() })));
+		}
+
 		// Check difficulty is correct given the two timestamps.
-		let expected_difficulty = self.calculate_difficulty(header, parent);
+		let expected_difficulty = self.calculate_difficulty(header, parent)?;
 		if header.difficulty() != &expected_difficulty {
 			return Err(From::from(BlockError::InvalidDifficulty(Mismatch { expected: expected_difficulty, found: header.difficulty().clone() })))
 		}
@@ -242,11 +382,67 @@ impl Ethash {
 }
 
 impl Ethash {
-	fn calculate_difficulty(&self, header: &Header, parent: &Header) -> U256 {
+	/// Base reward of the block with the given number, after reward
+	/// transitions and ECIP-1017 era reductions.
+	pub fn block_reward(&self, number: BlockNumber) -> U256 {
+		self.era_and_reward(number).1
+	}
+
+	/// Rewards bestowed by the block with the given number, which includes
+	/// uncles with the given numbers. Uncles are assumed to be valid.
+	pub fn block_rewards(&self, number: BlockNumber, uncles: &[BlockNumber]) -> BlockRewards {
+		let (eras, reward) = self.era_and_reward(number);
+		let author = reward + (reward >> 5) * U256::from(uncles.len());
+		let uncles = uncles.iter().map(|uncle| {
+			if eras == 0 {
+				// 7/8 of the reward one generation back, down to 2/8 six back.
+				let depth = number.saturating_sub(*uncle);
+				(reward * U256::from(8u64.saturating_sub(depth))) >> 3
+			} else {
+				reward >> 5
+			}
+		}).collect();
+
+		BlockRewards { author, uncles }
+	}
+
+	/// Total ether issued by the blocks from `from` to `to` inclusive.
+	/// `uncles` is called for every block and returns the numbers of the
+	/// uncles it includes.
+	pub fn total_issuance<F>(&self, from: BlockNumber, to: BlockNumber, mut uncles: F) -> U256
+		where F: FnMut(BlockNumber) -> Vec<BlockNumber>
+	{
+		(from..=to).fold(U256::zero(), |total, number| {
+			total + self.block_rewards(number, &uncles(number)).total()
+		})
+	}
+
+	fn era_and_reward(&self, number: BlockNumber) -> (u64, U256) {
+		let reward = self.ethash_params.block_reward.range(..=number)
+			.next_back()
+			.map_or_else(U256::zero, |(_, reward)| *reward);
+		ecip1017_eras_block_reward(self.ethash_params.ecip1017_era_rounds, reward, number)
+	}
+
+	/// Difficulty `header` must have to follow `parent`.
+	///
+	/// Fails for the genesis block, for headers older than their parent and
+	/// if the difficulty doesn't fit in 256 bits, so headers from untrusted
+	/// sources can be passed as they are.
+	pub fn calculate_difficulty(&self, header: &Header, parent: &Header) -> Result<U256, Error> {
 		const EXP_DIFF_PERIOD: u64 = 100_000;
 		if header.number() == 0 {
-			panic!("Can't calculate genesis block difficulty");
+			return Err(From::from(BlockError::RidiculousNumber(OutOfBounds { min: Some(1), max: None, found: header.number() })));
 		}
+		let timestamp_diff = match header.timestamp().checked_sub(parent.timestamp()) {
+			Some(diff) => diff,
+			None => {
+				let min = timestamp_to_time(parent.timestamp())?;
+				let found = timestamp_to_time(header.timestamp())?;
+				return Err(From::from(BlockError::InvalidTimestamp(OutOfBounds { max: None, min: Some(min), found })));
+			},
+		};
+		let overflow = || Error::from(BlockError::DifficultyOverflow);
 
 		let parent_has_uncles = parent.uncles_hash() != &KECCAK_EMPTY_LIST_RLP;
 
@@ -268,11 +464,13 @@ impl Ethash {
 
 		let frontier_limit = self.ethash_params.homestead_transition;
 
+		let parent_difficulty = *parent.difficulty();
+		let adjustment = parent_difficulty / difficulty_bound_divisor;
 		let mut target = if header.number() < frontier_limit {
-			if header.timestamp() >= parent.timestamp() + duration_limit {
-				*parent.difficulty() - (*parent.difficulty() / difficulty_bound_divisor)
+			if timestamp_diff >= duration_limit {
+				parent_difficulty - adjustment
 			} else {
-				*parent.difficulty() + (*parent.difficulty() / difficulty_bound_divisor)
+				parent_difficulty.checked_add(adjustment).ok_or_else(overflow)?
 			}
 		} else {
 			//trace!(target: "ethash", "Calculating difficulty parent.difficulty={}, header.timestamp={}, parent.timestamp={}", parent.difficulty(), header.timestamp(), parent.timestamp());
@@ -285,19 +483,19 @@ impl Ethash {
 				(self.ethash_params.metropolis_difficulty_increment_divisor, 1)
 			};
 
-			let diff_inc = (header.timestamp() - parent.timestamp()) / increment_divisor;
+			let diff_inc = timestamp_diff / increment_divisor;
 			if diff_inc <= threshold {
-				*parent.difficulty() + *parent.difficulty() / difficulty_bound_divisor * U256::from(threshold - diff_inc)
+				adjustment.checked_mul(U256::from(threshold - diff_inc))
+					.and_then(|increase| parent_difficulty.checked_add(increase))
+					.ok_or_else(overflow)?
 			} else {
 				let multiplier: U256 = cmp::min(diff_inc - threshold, 99).into();
-				parent.difficulty().saturating_sub(
-					*parent.difficulty() / difficulty_bound_divisor * multiplier
-				)
+				parent_difficulty.saturating_sub(adjustment.checked_mul(multiplier).ok_or_else(overflow)?)
 			}
 		};
 		target = cmp::max(min_difficulty, target);
 		if header.number() < self.ethash_params.bomb_defuse_transition {
-			if header.number() < self.ethash_params.ecip1010_pause_transition {
+			let period = if header.number() < self.ethash_params.ecip1010_pause_transition {
 				let mut number = header.number();
 				let original_number = number;
 				for (block, delay) in &self.ethash_params.difficulty_bomb_delays {
@@ -305,24 +503,47 @@ impl Ethash {
 						number = number.saturating_sub(*delay);
 					}
 				}
-				let period = (number / EXP_DIFF_PERIOD) as usize;
-				if period > 1 {
-					target = cmp::max(min_difficulty, target + (U256::from(1) << (period - 2)));
-				}
+				number / EXP_DIFF_PERIOD
 			} else if header.number() < self.ethash_params.ecip1010_continue_transition {
-				let fixed_difficulty = ((self.ethash_params.ecip1010_pause_transition / EXP_DIFF_PERIOD) - 2) as usize;
-				target = cmp::max(min_difficulty, target + (U256::from(1) << fixed_difficulty));
+				self.ethash_params.ecip1010_pause_transition / EXP_DIFF_PERIOD
 			} else {
-				let period = ((parent.number() + 1) / EXP_DIFF_PERIOD) as usize;
-				let delay = ((self.ethash_params.ecip1010_continue_transition - self.ethash_params.ecip1010_pause_transition) / EXP_DIFF_PERIOD) as usize;
-				target = cmp::max(min_difficulty, target + (U256::from(1) << (period - delay - 2)));
+				let period = parent.number().saturating_add(1) / EXP_DIFF_PERIOD;
+				let delay = self.ethash_params.ecip1010_continue_transition
+					.saturating_sub(self.ethash_params.ecip1010_pause_transition) / EXP_DIFF_PERIOD;
+				period.saturating_sub(delay)
+			};
+			// the bomb goes off from the third period on.
+			if period > 1 {
+				let exponent = period - 2;
+				if exponent >= 256 {
+					return Err(overflow());
+				}
+				let bomb = U256::from(1) << (exponent as usize);
+				target = cmp::max(min_difficulty, target.checked_add(bomb).ok_or_else(overflow)?);
 			}
 		}
-		target
+		Ok(target)
 	}
 }
 
+/// Reject headers numbered past the epochs we are willing to build a cache for.
+fn verify_block_number(header: &Header) -> Result<(), BlockError> {
+	if header.number() > MAX_BLOCK_NUMBER {
+		return Err(BlockError::RidiculousNumber(OutOfBounds { min: None, max: Some(MAX_BLOCK_NUMBER), found: header.number() }));
+	}
+	Ok(())
+}
+
+fn timestamp_to_time(timestamp: u64) -> Result<SystemTime, BlockError> {
+	UNIX_EPOCH.checked_add(Duration::from_secs(timestamp)).ok_or(BlockError::TimestampOverflow)
+}
+
+/// Reduce `reward` by 20% for every completed ECIP-1017 era. Returns the
+/// era and the reduced reward. An era length of zero disables reductions.
 fn ecip1017_eras_block_reward(era_rounds: u64, mut reward: U256, block_number:u64) -> (u64, U256) {
+	if era_rounds == 0 {
+		return (0, reward);
+	}
 	let eras = if block_number != 0 && block_number % era_rounds == 0 {
 		block_number / era_rounds - 1
 	} else {
@@ -330,9 +551,441 @@ fn ecip1017_eras_block_reward(era_rounds: u64, mut reward: U256, block_number:u6
 	};
 	let mut divi = U256::from(1);
 	for _ in 0..eras {
-		reward = reward * U256::from(4);
-		divi = divi * U256::from(5);
+		match (reward.checked_mul(U256::from(4)), divi.checked_mul(U256::from(5))) {
+			(Some(multiplied), Some(divisor)) => {
+				reward = multiplied;
+				divi = divisor;
+			},
+			// far enough out the exact fraction no longer fits, so settle
+			// what we have and carry on one era at a time.
+			_ => {
+				reward = reward / divi / U256::from(5) * U256::from(4);
+				divi = U256::from(1);
+			},
+		}
+		// the reward shrinks by a fifth every era, so this ends the loop
+		// long before absurd block numbers run out of eras.
+		if reward.is_zero() {
+			break;
+		}
 	}
 	reward = reward / divi;
 	(eras, reward)
 }
+
+#[cfg(test)]
+mod tests {
+	use std::collections::BTreeMap;
+	use std::sync::Arc;
+	use std::time::{Duration, UNIX_EPOCH};
+	use ethereum_types::{H256, H64, U256};
+	use tempdir::TempDir;
+	use jsonrpc_core::serde_json::from_str;
+	use crate::clock::ManualClock;
+	use crate::engine::Engine;
+	use crate::error::{BlockError, Error, ErrorKind};
+	use crate::header::{BlockNumber, Header};
+	use super::{CommonParams, Ethash, EthashParams};
+
+	fn test_params() -> CommonParams {
+		CommonParams {
+			maximum_extra_data_size: 32,
+			min_gas_limit: 5000.into(),
+			gas_limit_bound_divisor: 1024.into(),
+		}
+	}
+
+	fn test_ethash_params() -> EthashParams {
+		EthashParams {
+			minimum_difficulty: 131_072.into(),
+			difficulty_bound_divisor: 2048.into(),
+			difficulty_increment_divisor: 10,
+			metropolis_difficulty_increment_divisor: 9,
+			duration_limit: 13,
+			homestead_transition: 0,
+			difficulty_hardfork_transition: u64::MAX,
+			difficulty_hardfork_bound_divisor: 2048.into(),
+			bomb_defuse_transition: u64::MAX,
+			eip100b_transition: u64::MAX,
+			ecip1010_pause_transition: u64::MAX,
+			ecip1010_continue_transition: u64::MAX,
+			ecip1017_era_rounds: u64::MAX,
+			block_reward: BTreeMap::new(),
+			expip2_transition: u64::MAX,
+			expip2_duration_limit: 30,
+			block_reward_contract_transition: 0,
+			difficulty_bomb_delays: BTreeMap::new(),
+			dao_hardfork_transition: 100,
+			dao_hardfork_support: true,
+		}
+	}
+
+	/// An engine whose clock reads 2000 seconds after the epoch.
+	fn test_engine(tempdir: &TempDir) -> (Arc<Ethash>, Arc<ManualClock>) {
+		let clock = Arc::new(ManualClock::new(UNIX_EPOCH + Duration::from_secs(2_000)));
+		let engine = Ethash::with_clock(tempdir.path(), test_params(), test_ethash_params(), None, clock.clone());
+		(engine, clock)
+	}
+
+	/// A child of `parent` with the expected difficulty for a 10 second gap.
+	fn child(engine: &Ethash, parent: &Header) -> Header {
+		let mut header = Header::new();
+		header.set_number(parent.number() + 1);
+		header.set_timestamp(parent.timestamp() + 10);
+		header.set_gas_limit(*parent.gas_limit());
+		let difficulty = engine.calculate_difficulty(&header, parent).unwrap();
+		header.set_difficulty(difficulty);
+		header
+	}
+
+	fn parent() -> Header {
+		let mut parent = Header::new();
+		parent.set_number(1);
+		parent.set_timestamp(1_000);
+		parent.set_difficulty(131_072.into());
+		parent.set_gas_limit(1_024_000.into());
+		parent
+	}
+
+	/// Whether basic verification got as far as the seal, which the test
+	/// headers lack.
+	fn passes_header_checks(engine: &Ethash, header: &Header) -> bool {
+		matches!(
+			engine.verify_block_basic(header),
+			Err(Error(ErrorKind::Block(BlockError::InvalidSealArity(_)), _))
+		)
+	}
+
+	#[test]
+	fn rejects_too_much_gas_used() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let (engine, _) = test_engine(&tempdir);
+		let mut header = parent();
+		header.set_gas_used(1_024_001.into());
+
+		match engine.verify_block_basic(&header) {
+			Err(Error(ErrorKind::Block(BlockError::TooMuchGasUsed(oob)), _)) => {
+				assert_eq!(oob.max, Some(1_024_000.into()));
+				assert_eq!(oob.found, 1_024_001.into());
+			},
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn rejects_gas_limit_below_minimum() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let (engine, _) = test_engine(&tempdir);
+		let mut header = parent();
+		header.set_gas_limit(4999.into());
+
+		match engine.verify_block_basic(&header) {
+			Err(Error(ErrorKind::Block(BlockError::InvalidGasLimit(oob)), _)) => {
+				assert_eq!(oob.min, Some(5000.into()));
+			},
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn bounds_gas_limit_change_by_parent() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let (engine, _) = test_engine(&tempdir);
+		let parent = parent();
+		let mut header = child(&engine, &parent);
+
+		// the limit may move by less than parent / 1024 = 1000 either way.
+		for gas_limit in &[1_023_001u64, 1_024_000, 1_024_999] {
+			header.set_gas_limit(U256::from(*gas_limit));
+			assert!(engine.verify_block_family(&header, &parent).is_ok());
+		}
+		for gas_limit in &[1_023_000u64, 1_025_000] {
+			header.set_gas_limit(U256::from(*gas_limit));
+			match engine.verify_block_family(&header, &parent) {
+				Err(Error(ErrorKind::Block(BlockError::InvalidGasLimit(oob)), _)) => {
+					assert_eq!(oob.min, Some(1_023_000.into()));
+					assert_eq!(oob.max, Some(1_025_000.into()));
+				},
+				other => panic!("unexpected result: {:?}", other),
+			}
+		}
+	}
+
+	#[test]
+	fn rejects_future_timestamps() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let (engine, clock) = test_engine(&tempdir);
+		let mut header = parent();
+
+		header.set_timestamp(2_015);
+		assert!(passes_header_checks(&engine, &header));
+
+		header.set_timestamp(2_016);
+		match engine.verify_block_basic(&header) {
+			Err(Error(ErrorKind::Block(BlockError::TemporarilyInvalid(_)), _)) => {},
+			other => panic!("unexpected result: {:?}", other),
+		}
+		clock.advance(Duration::from_secs(1));
+		assert!(passes_header_checks(&engine, &header));
+
+		header.set_timestamp(2_001 + 150 + 1);
+		match engine.verify_block_basic(&header) {
+			Err(Error(ErrorKind::Block(BlockError::InvalidTimestamp(_)), _)) => {},
+			other => panic!("unexpected result: {:?}", other),
+		}
+
+		header.set_timestamp(u64::MAX);
+		match engine.verify_block_basic(&header) {
+			Err(Error(ErrorKind::Block(BlockError::TimestampOverflow), _)) => {},
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn rejects_timestamp_not_after_parent() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let (engine, _) = test_engine(&tempdir);
+		let parent = parent();
+		let mut header = child(&engine, &parent);
+		assert!(engine.verify_block_family(&header, &parent).is_ok());
+
+		header.set_timestamp(parent.timestamp());
+		match engine.verify_block_family(&header, &parent) {
+			Err(Error(ErrorKind::Block(BlockError::InvalidTimestamp(oob)), _)) => {
+				assert_eq!(oob.min, Some(UNIX_EPOCH + Duration::from_secs(1_001)));
+				assert_eq!(oob.found, UNIX_EPOCH + Duration::from_secs(1_000));
+			},
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn rejects_oversized_extra_data() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let (engine, _) = test_engine(&tempdir);
+		let mut header = parent();
+
+		header.set_extra_data(vec![0; 32]);
+		assert!(passes_header_checks(&engine, &header));
+
+		header.set_extra_data(vec![0; 33]);
+		match engine.verify_block_basic(&header) {
+			Err(Error(ErrorKind::Block(BlockError::ExtraDataOutOfBounds(oob)), _)) => {
+				assert_eq!(oob.max, Some(32));
+				assert_eq!(oob.found, 33);
+			},
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn rejects_ridiculous_numbers() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let (engine, _) = test_engine(&tempdir);
+		let mut header = parent();
+
+		header.set_number(2048 * 30_000 - 1);
+		assert!(passes_header_checks(&engine, &header));
+
+		// a well-formed seal, so only the number stands between the header
+		// and the ethash cache.
+		header.set_number(u64::MAX);
+		header.set_seal(vec![rlp::encode(&H256::zero()).to_vec(), rlp::encode(&H64::zero()).to_vec()]);
+		for result in [engine.verify_block_basic(&header), engine.verify_block_unordered(&header)].iter() {
+			match result {
+				Err(Error(ErrorKind::Block(BlockError::RidiculousNumber(oob)), _)) => {
+					assert_eq!(oob.max, Some(2048 * 30_000 - 1));
+					assert_eq!(oob.found, u64::MAX);
+				},
+				other => panic!("unexpected result: {:?}", other),
+			}
+		}
+	}
+
+	#[test]
+	fn checks_dao_extra_data() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let mut ethash_params = test_ethash_params();
+		ethash_params.dao_hardfork_support = false;
+		let classic = Ethash::new(tempdir.path(), test_params(), ethash_params, None);
+		let (ethereum, _) = test_engine(&tempdir);
+
+		let mut header = parent();
+		for &(number, in_window) in &[(99, false), (100, true), (109, true), (110, false)] {
+			header.set_number(number);
+
+			header.set_extra_data(b"dao-hard-fork".to_vec());
+			assert!(passes_header_checks(&ethereum, &header));
+			assert_eq!(passes_header_checks(&classic, &header), !in_window);
+
+			header.set_extra_data(b"classic".to_vec());
+			assert_eq!(passes_header_checks(&ethereum, &header), !in_window);
+			assert!(passes_header_checks(&classic, &header));
+		}
+
+		header.set_number(100);
+		match ethereum.verify_block_basic(&header) {
+			Err(Error(ErrorKind::Block(BlockError::InvalidDaoExtraData { required: true, found }), _)) =>
+				assert_eq!(found, b"classic".to_vec()),
+			other => panic!("expected missing DAO extra data, got {:?}", other),
+		}
+		header.set_extra_data(b"dao-hard-fork".to_vec());
+		match classic.verify_block_basic(&header) {
+			Err(Error(ErrorKind::Block(BlockError::InvalidDaoExtraData { required: false, found }), _)) =>
+				assert_eq!(found, b"dao-hard-fork".to_vec()),
+			other => panic!("expected unexpected DAO extra data, got {:?}", other),
+		}
+	}
+
+	#[test]
+	fn dao_hardfork_support_from_spec() {
+		let params = |extra: &str| -> EthashParams {
+			let json = format!(
+				r#"{{"minimumDifficulty": "0x020000", "difficultyBoundDivisor": "0x0800", "daoHardforkTransition": "0x1d4c00"{}}}"#,
+				extra,
+			);
+			from_str::<ethjson::spec::EthashParams>(&json).unwrap().into()
+		};
+
+		assert!(params("").dao_hardfork_support);
+		assert!(params(r#", "daoHardforkSupport": true"#).dao_hardfork_support);
+		assert!(!params(r#", "daoHardforkSupport": false"#).dao_hardfork_support);
+	}
+
+	fn ether(value: u64) -> U256 {
+		U256::from(value) * U256::from(1_000_000_000_000_000_000u64)
+	}
+
+	#[test]
+	fn rewards_follow_transitions() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let mut ethash_params = test_ethash_params();
+		ethash_params.block_reward = vec![(0, ether(5)), (4_370_000, ether(3)), (7_280_000, ether(2))]
+			.into_iter()
+			.collect();
+		let engine = Ethash::new(tempdir.path(), test_params(), ethash_params, None);
+
+		assert_eq!(engine.block_reward(0), ether(5));
+		assert_eq!(engine.block_reward(4_369_999), ether(5));
+		assert_eq!(engine.block_reward(4_370_000), ether(3));
+		assert_eq!(engine.block_reward(9_000_000), ether(2));
+
+		// uncles one and six generations back.
+		let rewards = engine.block_rewards(4_370_000, &[4_369_999, 4_369_994]);
+		assert_eq!(rewards.author, ether(3) + ether(3) / 32 * 2);
+		assert_eq!(rewards.uncles, vec![ether(3) * 7 / 8, ether(3) * 2 / 8]);
+		assert_eq!(rewards.total(), rewards.author + ether(3) * 9 / 8);
+	}
+
+	#[test]
+	fn rewards_follow_ecip1017_eras() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let mut ethash_params = test_ethash_params();
+		ethash_params.block_reward = vec![(0, ether(5))].into_iter().collect();
+		ethash_params.ecip1017_era_rounds = 5_000_000;
+		let engine = Ethash::new(tempdir.path(), test_params(), ethash_params, None);
+
+		assert_eq!(engine.block_reward(5_000_000), ether(5));
+		assert_eq!(engine.block_reward(5_000_001), ether(4));
+		assert_eq!(engine.block_reward(10_000_001), ether(16) / 5);
+
+		// in later eras every uncle earns 1/32 of the reward.
+		let rewards = engine.block_rewards(5_000_001, &[5_000_000]);
+		assert_eq!(rewards.author, ether(4) + ether(4) / 32);
+		assert_eq!(rewards.uncles, vec![ether(4) / 32]);
+	}
+
+	#[test]
+	fn sums_issuance_over_range() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let mut ethash_params = test_ethash_params();
+		ethash_params.block_reward = vec![(0, ether(5)), (3, ether(3))].into_iter().collect();
+		let engine = Ethash::new(tempdir.path(), test_params(), ethash_params, None);
+
+		let issuance = engine.total_issuance(1, 4, |number| if number == 2 { vec![1] } else { vec![] });
+		let uncle_block = ether(5) + ether(5) / 32 + ether(5) * 7 / 8;
+		assert_eq!(issuance, ether(5) + uncle_block + ether(3) * 2);
+	}
+
+	#[test]
+	fn difficulty_of_bad_headers_is_an_error() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let (engine, _) = test_engine(&tempdir);
+		let parent = parent();
+
+		let mut genesis = parent.clone();
+		genesis.set_number(0);
+		match engine.calculate_difficulty(&genesis, &parent) {
+			Err(Error(ErrorKind::Block(BlockError::RidiculousNumber(_)), _)) => {},
+			other => panic!("unexpected result: {:?}", other),
+		}
+
+		let mut older = child(&engine, &parent);
+		older.set_timestamp(parent.timestamp() - 1);
+		match engine.calculate_difficulty(&older, &parent) {
+			Err(Error(ErrorKind::Block(BlockError::InvalidTimestamp(_)), _)) => {},
+			other => panic!("unexpected result: {:?}", other),
+		}
+
+		let mut heavy_parent = parent.clone();
+		heavy_parent.set_difficulty(U256::max_value());
+		let mut header = child(&engine, &parent);
+		header.set_timestamp(parent.timestamp() + 1);
+		match engine.calculate_difficulty(&header, &heavy_parent) {
+			Err(Error(ErrorKind::Block(BlockError::DifficultyOverflow), _)) => {},
+			other => panic!("unexpected result: {:?}", other),
+		}
+
+		// the bomb alone outgrows 256 bits.
+		let mut far_parent = parent.clone();
+		far_parent.set_number(u64::MAX - 2);
+		header.set_number(u64::MAX - 1);
+		match engine.calculate_difficulty(&header, &far_parent) {
+			Err(Error(ErrorKind::Block(BlockError::DifficultyOverflow), _)) => {},
+			other => panic!("unexpected result: {:?}", other),
+		}
+	}
+
+	#[test]
+	fn ecip1010_bomb_stays_off_until_due() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let mut ethash_params = test_ethash_params();
+		ethash_params.ecip1010_pause_transition = 100_000;
+		ethash_params.ecip1010_continue_transition = 500_000;
+		let engine = Ethash::new(tempdir.path(), test_params(), ethash_params, None);
+
+		// a 10 second gap leaves the difficulty as it is without the bomb.
+		let difficulty = |number: BlockNumber| {
+			let mut parent = parent();
+			parent.set_number(number - 1);
+			let mut header = Header::new();
+			header.set_number(number);
+			header.set_timestamp(parent.timestamp() + 10);
+			engine.calculate_difficulty(&header, &parent).unwrap()
+		};
+
+		assert_eq!(difficulty(100_000), 131_072.into());
+		assert_eq!(difficulty(500_000), 131_072.into());
+		assert_eq!(difficulty(600_000), U256::from(131_072 + 1));
+		assert_eq!(difficulty(700_000), U256::from(131_072 + 2));
+	}
+
+	#[test]
+	fn ecip1017_rewards_survive_absurd_numbers() {
+		let tempdir = TempDir::new("ethash").unwrap();
+		let mut ethash_params = test_ethash_params();
+		ethash_params.block_reward = vec![(0, ether(5))].into_iter().collect();
+		ethash_params.ecip1017_era_rounds = 1;
+		let engine = Ethash::new(tempdir.path(), test_params(), ethash_params, None);
+
+		assert_eq!(engine.block_reward(3), ether(5) * 16 / 25);
+		assert_eq!(engine.block_reward(u64::MAX), U256::zero());
+		assert_eq!(engine.block_rewards(u64::MAX, &[u64::MAX - 1]).total(), U256::zero());
+
+		let mut ethash_params = test_ethash_params();
+		ethash_params.block_reward = vec![(0, ether(5))].into_iter().collect();
+		ethash_params.ecip1017_era_rounds = 0;
+		let engine = Ethash::new(tempdir.path(), test_params(), ethash_params, None);
+		assert_eq!(engine.block_reward(u64::MAX), ether(5));
+	}
+}
@@ -0,0 +1,128 @@
+// Copyright 2019 Chainpool
+
+//! Fork-choice rules deciding which of two headers heads the best chain.
+//!
+//! Rules are written against the `parity_machine` header traits, so they
+//! work for any header which knows its total score and whether it is
+//! finalized. The header chain applies them to `ExtendedHeader`s.
+
+use parity_machine::{FinalizableHeader, TotalScoredHeader};
+
+/// Outcome of comparing a new header with the current best one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkChoice {
+    /// The new header becomes the best block.
+    New,
+    /// The current best block is kept.
+    Old,
+}
+
+/// Rule for picking the best chain.
+pub trait ForkChoiceRule<H>: Send + Sync {
+    /// Decide whether `new` should replace `best` as the best block.
+    fn fork_choice(&self, new: &H, best: &H) -> ForkChoice;
+}
+
+/// Heaviest chain rule for proof-of-work chains: the header with the greater
+/// total difficulty wins, ties keep the current best block.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TotalDifficulty;
+
+impl<H> ForkChoiceRule<H> for TotalDifficulty
+where
+    H: TotalScoredHeader,
+    H::Value: Ord,
+{
+    fn fork_choice(&self, new: &H, best: &H) -> ForkChoice {
+        if new.total_score() > best.total_score() {
+            ForkChoice::New
+        } else {
+            ForkChoice::Old
+        }
+    }
+}
+
+/// Rule for proof-of-authority chains.
+///
+/// A finalized header always wins over one which is not. Otherwise headers
+/// are compared by total score, which for step-based engines such as Aura
+/// encodes the number of steps taken.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProofOfAuthority;
+
+impl<H> ForkChoiceRule<H> for ProofOfAuthority
+where
+    H: TotalScoredHeader + FinalizableHeader,
+    <H as TotalScoredHeader>::Value: Ord,
+{
+    fn fork_choice(&self, new: &H, best: &H) -> ForkChoice {
+        // never moving off a finalized best block is ensured by the chain,
+        // which refuses routes retracting finalized blocks.
+        if new.is_finalized() && !best.is_finalized() {
+            return ForkChoice::New;
+        }
+        TotalDifficulty.fork_choice(new, best)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ForkChoice, ForkChoiceRule, ProofOfAuthority, TotalDifficulty};
+    use crate::header::{ExtendedHeader, Header};
+    use ethereum_types::U256;
+
+    fn extended(parent_td: u64, difficulty: u64, is_finalized: bool) -> ExtendedHeader {
+        let mut header = Header::new();
+        header.set_difficulty(U256::from(difficulty));
+        ExtendedHeader {
+            header,
+            is_finalized,
+            parent_total_difficulty: U256::from(parent_td),
+        }
+    }
+
+    #[test]
+    fn total_difficulty_prefers_heavier() {
+        let best = extended(1000, 100, false);
+        assert_eq!(
+            TotalDifficulty.fork_choice(&extended(1000, 101, false), &best),
+            ForkChoice::New
+        );
+        assert_eq!(
+            TotalDifficulty.fork_choice(&extended(1050, 50, false), &best),
+            ForkChoice::Old
+        );
+        // finality plays no part.
+        assert_eq!(
+            TotalDifficulty.fork_choice(&extended(900, 100, true), &best),
+            ForkChoice::Old
+        );
+    }
+
+    #[test]
+    fn proof_of_authority_prefers_finalized() {
+        let best = extended(1000, 100, false);
+        assert_eq!(
+            ProofOfAuthority.fork_choice(&extended(900, 100, true), &best),
+            ForkChoice::New
+        );
+        assert_eq!(
+            ProofOfAuthority.fork_choice(&extended(1000, 101, false), &best),
+            ForkChoice::New
+        );
+        assert_eq!(
+            ProofOfAuthority.fork_choice(&extended(1000, 99, false), &best),
+            ForkChoice::Old
+        );
+
+        let finalized_best = extended(1000, 100, true);
+        assert_eq!(
+            ProofOfAuthority.fork_choice(&extended(900, 100, true), &finalized_best),
+            ForkChoice::Old
+        );
+        assert_eq!(
+            ProofOfAuthority.fork_choice(&extended(1000, 101, false), &finalized_best),
+            ForkChoice::New
+        );
+    }
+}
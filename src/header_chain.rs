@@ -1,21 +1,2287 @@
 // Copyright 2019 Chainpool
 
+//! Light client header chain.
+//!
+//! Headers are linked by parent hash and every known header is kept as a
+//! candidate at its height. The canonical chain is picked by a fork-choice
+//! rule, by default the one with the greatest total difficulty; competing
+//! forks are retained so that a better fork can take over when it appears.
+//!
+//! All data lives in a `HeaderStore`, so the chain can be kept in memory or
+//! persisted across restarts. Instead of genesis, a chain may also start from
+//! a hardcoded checkpoint so that old headers need not be verified.
+//!
+//! Headers delivered out of order can be imported through `import`, which
+//! keeps headers with an unknown parent in a bounded orphan pool until the
+//! parent arrives.
+//!
+//! A finality depth may be configured: canonical blocks at least that deep
+//! below the best block are finalized and are never reorganized away. The
+//! engine may finalize headers on its own as well, e.g. by validator
+//! signatures, which the `ProofOfAuthority` fork choice takes into account.
+//! A maximum reorganization depth may be configured as well: a fork which
+//! would retract more blocks is quarantined instead of being adopted.
+//!
+//! Subscribers registered with `add_notify` are told about enacted,
+//! retracted and finalized headers as they happen.
+//!
+//! Old headers may be pruned a CHT at a time, keeping only the CHT root. The
+//! canonical hash and total difficulty of a pruned block can still be obtained
+//! by supplying an inclusion proof against that root.
+
+use crate::cht;
 use crate::encoded;
+use crate::engine::Engine;
+use crate::error::{BlockError, Error, ErrorKind};
+use crate::fork_choice::{ForkChoice, ForkChoiceRule, TotalDifficulty};
+use crate::header::{BlockNumber, ExtendedHeader, Header};
+use crate::header_store::{Batch, HeaderStore, MemoryStore};
+use crate::notify::ChainNotify;
+use crate::orphan_pool::{OrphanPool, SenderId};
+use crate::verification_queue::VerificationQueue;
+use bytes::Bytes;
 use ethereum_types::{H256, U256};
+use rlp::{Decodable, DecoderError, Encodable, Rlp, RlpStream};
+use rustc_hex::FromHex;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use types::blockchain_info::BlockChainInfo;
+use types::ids::BlockId;
+use types::pruning_info::PruningInfo;
+use types::tree_route::TreeRoute;
+use unexpected::OutOfBounds;
 
+/// Description of a block in the header chain.
+#[derive(Debug, Clone, PartialEq)]
 pub struct BlockDescriptor {
     pub hash: H256,
     pub number: u64,
     pub total_difficulty: U256,
 }
 
-struct Candidate {
-    hash: H256,
-    parent_hash: H256,
-    total_difficulty: U256,
+/// A header which may or may not be canonical.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    /// Hash of the header.
+    pub hash: H256,
+    /// Hash of the header's parent.
+    pub parent_hash: H256,
+    /// Total difficulty including this header.
+    pub total_difficulty: U256,
+}
+
+/// All known headers at a given height.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// Every header known at this height.
+    pub candidates: Vec<Candidate>,
+    /// Hash of the canonical header at this height.
+    pub canonical_hash: H256,
+}
+
+impl Encodable for Entry {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2);
+        s.append(&self.canonical_hash);
+        s.begin_list(self.candidates.len());
+        for candidate in &self.candidates {
+            s.begin_list(3)
+                .append(&candidate.hash)
+                .append(&candidate.parent_hash)
+                .append(&candidate.total_difficulty);
+        }
+    }
+}
+
+impl Decodable for Entry {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        let candidates = rlp
+            .at(1)?
+            .iter()
+            .map(|item| {
+                Ok(Candidate {
+                    hash: item.val_at(0)?,
+                    parent_hash: item.val_at(1)?,
+                    total_difficulty: item.val_at(2)?,
+                })
+            })
+            .collect::<Result<Vec<_>, DecoderError>>()?;
+
+        if candidates.is_empty() {
+            return Err(DecoderError::Custom("Empty candidates vector submitted."));
+        }
+
+        Ok(Entry {
+            candidates,
+            canonical_hash: rlp.val_at(0)?,
+        })
+    }
+}
+
+/// Data necessary to start the header chain from a checkpoint instead of
+/// from genesis.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HardcodedSync {
+    /// The header of the block to start from.
+    pub header: encoded::Header,
+    /// Total difficulty including the block of `header`.
+    pub total_difficulty: U256,
+    /// Ordered CHT roots of the blocks before and including `header`.
+    pub chts: Vec<H256>,
+}
+
+impl HardcodedSync {
+    /// Convert a checkpoint loaded from a spec file.
+    pub fn from_json(json: ethjson::spec::HardcodedSync) -> Result<Self, Error> {
+        let header = json
+            .header
+            .from_hex()
+            .map_err(|_| DecoderError::Custom("Invalid hex in hardcoded sync header."))?;
+        let header = encoded::Header::new(header);
+        // make sure the header is well-formed before trusting the views onto it.
+        header.decode()?;
+
+        Ok(HardcodedSync {
+            header,
+            total_difficulty: json.total_difficulty.into(),
+            chts: json.chts.into_iter().map(Into::into).collect(),
+        })
+    }
 }
 
-pub struct HeaderChain {
+/// Which headers the chain keeps in full.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Pruning {
+    /// Keep every header.
+    #[default]
+    Archive,
+    /// Keep at least the given number of most recent headers. Older headers
+    /// are discarded a whole CHT at a time, keeping only the CHT root.
+    KeepRecent(u64),
+}
+
+/// Maximum number of quarantined headers kept for inspection.
+const MAX_QUARANTINED: usize = 1024;
+
+/// Headers of forks which would have caused a reorganization deeper than
+/// allowed, along with that depth. The oldest are dropped once full.
+#[derive(Default)]
+struct Quarantine {
+    headers: HashMap<H256, (Header, u64)>,
+    order: VecDeque<H256>,
+}
+
+impl Quarantine {
+    fn insert(&mut self, header: Header, depth: u64) {
+        if self.order.len() == MAX_QUARANTINED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.headers.remove(&oldest);
+            }
+        }
+        let hash = header.hash();
+        if self.headers.insert(hash, (header, depth)).is_none() {
+            self.order.push_back(hash);
+        }
+    }
+
+    fn depth(&self, hash: &H256) -> Option<u64> {
+        self.headers.get(hash).map(|(_, depth)| *depth)
+    }
+}
+
+/// Outcome of importing a header with `HeaderChain::import`.
+#[derive(Debug)]
+pub enum ImportOutcome {
+    /// The header's parent is unknown, so it was queued as an orphan.
+    Queued,
+    /// The header was inserted, along with any orphans waiting for it.
+    Imported {
+        /// Hashes of the inserted headers, parents before children.
+        headers: Vec<H256>,
+        /// The route from the previous best block to the new one, if the
+        /// best block changed.
+        route: Option<TreeRoute>,
+    },
+}
+
+/// Outcome of `HeaderChain::import_verified`.
+#[derive(Debug, Default)]
+pub struct ImportedHeaders {
+    /// Hashes of the inserted headers, in queue order.
+    pub imported: Vec<H256>,
+    /// Headers which failed verification or could not be inserted.
+    pub bad: Vec<(H256, Error)>,
+}
+
+pub struct HeaderChain<E> {
+    engine: Arc<E>,
     genesis_header: encoded::Header,
+    first_block: BlockDescriptor,
     best_block: BlockDescriptor,
+    pruning: Pruning,
+    fork_choice: Box<dyn ForkChoiceRule<ExtendedHeader>>,
+    finality_depth: Option<u64>,
+    max_reorg_depth: Option<u64>,
+    quarantine: Quarantine,
+    /// Number of the most recent finalized block. Never moves backwards
+    /// while a finality depth is set.
+    finalized: Option<BlockNumber>,
+    orphans: OrphanPool,
+    notify: Vec<Arc<dyn ChainNotify>>,
+    /// Index of the next CHT to be built when pruning.
+    next_cht: u64,
+    store: Box<dyn HeaderStore>,
+}
+
+impl<E: Engine> HeaderChain<E> {
+    /// Create a new in-memory header chain starting from the given genesis header.
+    pub fn new(engine: Arc<E>, genesis_header: encoded::Header) -> Self {
+        Self::with_store(engine, genesis_header, Box::new(MemoryStore::new()))
+            .expect("writes to an in-memory store never fail; qed")
+    }
+
+    /// Create a header chain backed by the given store.
+    ///
+    /// If the store already holds a chain, it is resumed from the stored best
+    /// block. Otherwise the genesis header is written to the store.
+    pub fn with_store(
+        engine: Arc<E>,
+        genesis_header: encoded::Header,
+        store: Box<dyn HeaderStore>,
+    ) -> Result<Self, Error> {
+        Self::open(engine, genesis_header, None, store)
+    }
+
+    /// Create a header chain backed by the given store which starts from a
+    /// hardcoded checkpoint.
+    ///
+    /// Headers before the checkpoint are never imported; the checkpoint's CHT
+    /// roots are recorded instead. If the store already holds a chain, it is
+    /// resumed from the stored best block and the checkpoint is ignored.
+    pub fn with_checkpoint(
+        engine: Arc<E>,
+        genesis_header: encoded::Header,
+        checkpoint: HardcodedSync,
+        store: Box<dyn HeaderStore>,
+    ) -> Result<Self, Error> {
+        Self::open(engine, genesis_header, Some(checkpoint), store)
+    }
+
+    fn open(
+        engine: Arc<E>,
+        genesis_header: encoded::Header,
+        checkpoint: Option<HardcodedSync>,
+        mut store: Box<dyn HeaderStore>,
+    ) -> Result<Self, Error> {
+        if store.best_block().is_none() {
+            let mut batch = Batch::default();
+            batch.insert_header(genesis_header.hash(), genesis_header.clone());
+
+            let (root, total_difficulty) = match checkpoint {
+                Some(checkpoint) => {
+                    for (index, root) in checkpoint.chts.iter().enumerate() {
+                        batch.insert_cht_root(index as u64, *root);
+                    }
+                    (checkpoint.header, checkpoint.total_difficulty)
+                }
+                None => {
+                    let total_difficulty = genesis_header.difficulty();
+                    (genesis_header.clone(), total_difficulty)
+                }
+            };
+
+            let hash = root.hash();
+            batch.insert_entry(
+                root.number(),
+                Entry {
+                    candidates: vec![Candidate {
+                        hash,
+                        parent_hash: root.parent_hash(),
+                        total_difficulty,
+                    }],
+                    canonical_hash: hash,
+                },
+            );
+            batch.insert_header(hash, root);
+            batch.set_best_block(hash);
+            batch.set_first_block(hash);
+            store.write(batch)?;
+        }
+
+        let best_block = store
+            .best_block()
+            .ok_or_else(|| corrupt_store("best block pointer is missing"))?;
+        let first_block = store
+            .first_block()
+            .ok_or_else(|| corrupt_store("first block pointer is missing"))?;
+
+        let first_block = load_descriptor(&*store, first_block)?;
+        let mut next_cht = first_prunable_cht(first_block.number);
+        while store.cht_root(next_cht).is_some() {
+            next_cht += 1;
+        }
+
+        Ok(HeaderChain {
+            engine,
+            genesis_header,
+            first_block,
+            best_block: load_descriptor(&*store, best_block)?,
+            pruning: Pruning::default(),
+            fork_choice: Box::new(TotalDifficulty),
+            finality_depth: None,
+            max_reorg_depth: None,
+            quarantine: Quarantine::default(),
+            finalized: None,
+            orphans: OrphanPool::default(),
+            notify: Vec::new(),
+            next_cht,
+            store,
+        })
+    }
+
+    /// Insert a header which passed the basic and unordered verification
+    /// stages, e.g. in a `VerificationQueue`.
+    ///
+    /// The parent of the header must already be in the chain, and the header
+    /// is checked against it by the engine's family stage. Inserting a
+    /// header which is already known is a no-op. If the fork-choice rule
+    /// prefers the new header over the best block, it becomes the new best
+    /// block and the canonical chain is rewritten down to the common ancestor.
+    /// A header which would become best but can't be routed to, because the
+    /// store lacks one of its ancestors, is refused.
+    ///
+    /// Returns the route from the previous best block to the new one when the
+    /// best block changed. Blocks before `index` in the route were retracted,
+    /// the rest were enacted.
+    pub fn insert(&mut self, header: Header) -> Result<Option<TreeRoute>, Error> {
+        let hash = header.hash();
+        let number = header.number();
+        let parent_hash = *header.parent_hash();
+
+        if self.is_known(&hash) {
+            return Ok(None);
+        }
+        if let (Some(depth), Some(max)) =
+            (self.quarantine.depth(&parent_hash), self.max_reorg_depth)
+        {
+            // descendants of a quarantined header join it.
+            self.quarantine.insert(header, depth);
+            return Err(ErrorKind::ReorgTooDeep(depth, max).into());
+        }
+
+        let parent_td = number
+            .checked_sub(1)
+            .and_then(|parent_number| self.store.entry(parent_number))
+            .and_then(|entry| entry.candidates.into_iter().find(|c| c.hash == parent_hash))
+            .map(|c| c.total_difficulty);
+        let parent_td = parent_td.ok_or(BlockError::UnknownParent(parent_hash))?;
+
+        let parent = self
+            .store
+            .header(&parent_hash)
+            .ok_or_else(|| corrupt_store("stored block header is missing"))?
+            .decode()?;
+        self.engine.verify_block_family(&header, &parent)?;
+
+        let total_difficulty = parent_td.checked_add(*header.difficulty()).ok_or_else(|| {
+            BlockError::DifficultyOutOfBounds(OutOfBounds {
+                min: None,
+                max: Some(U256::max_value() - parent_td),
+                found: *header.difficulty(),
+            })
+        })?;
+
+        let mut entry = self.store.entry(number).unwrap_or_else(|| Entry {
+            candidates: Vec::new(),
+            canonical_hash: hash,
+        });
+        entry.candidates.push(Candidate {
+            hash,
+            parent_hash,
+            total_difficulty,
+        });
+
+        let mut batch = Batch::default();
+        batch.insert_header(hash, header.encoded());
+
+        // only the engine can tell whether a header which is not canonical
+        // yet is final.
+        let is_finalized = self.engine.is_finalized(&header);
+        let new_header = ExtendedHeader {
+            header,
+            is_finalized,
+            parent_total_difficulty: parent_td,
+        };
+        let best_header = self
+            .extended_header(&self.best_block.hash)
+            .expect("best block header is always stored; qed");
+        if self.fork_choice.fork_choice(&new_header, &best_header) == ForkChoice::Old {
+            batch.insert_entry(number, entry);
+            self.store.write(batch)?;
+            return Ok(None);
+        }
+
+        // the new header is a child of `parent_hash`, so the route to it is
+        // the route to its parent followed by the header itself. Without a
+        // route the reorg limits can't be checked, so the header is refused.
+        let mut route = self
+            .tree_route(self.best_block.hash, parent_hash)
+            .ok_or_else(|| corrupt_store("no route from the best block to the new header"))?;
+        if route.is_from_route_finalized {
+            // switching would retract a finalized block, so the fork is only
+            // kept as a candidate.
+            batch.insert_entry(number, entry);
+            self.store.write(batch)?;
+            return Ok(None);
+        }
+        if let Some(max) = self.max_reorg_depth {
+            let depth = route.index as u64;
+            if depth > max {
+                self.quarantine.insert(new_header.header, depth);
+                return Err(ErrorKind::ReorgTooDeep(depth, max).into());
+            }
+        }
+        route.blocks.push(hash);
+
+        entry.canonical_hash = hash;
+        batch.insert_entry(number, entry);
+        self.set_canonical(&mut batch, parent_hash, number - 1);
+        batch.set_best_block(hash);
+        self.store.write(batch)?;
+
+        self.best_block = BlockDescriptor {
+            hash,
+            number,
+            total_difficulty,
+        };
+
+        let (retracted, enacted) = route.blocks.split_at(route.index);
+        for notify in &self.notify {
+            if !retracted.is_empty() {
+                notify.retracted(retracted);
+            }
+            notify.enacted(enacted);
+        }
+        if is_finalized {
+            self.finalize(number);
+        }
+        self.update_finality();
+        self.prune()?;
+
+        Ok(Some(route))
+    }
+
+    /// Roll the best block back to an ancestor on the canonical chain.
+    ///
+    /// Every block after the target on the canonical chain is removed, along
+    /// with forks branching off them, so they can be imported again later.
+    /// Subscribers are notified of the retracted blocks. Fails with
+    /// `ErrorKind::RevertBelowBoundary` for targets before the finalized
+    /// block or the first block the chain holds in full, and with
+    /// `ErrorKind::UnavailableBlock` for numbers without a canonical block.
+    ///
+    /// Returns the retracted blocks, children first.
+    pub fn revert_to(&mut self, target: BlockId) -> Result<Vec<H256>, Error> {
+        let hash = match target {
+            BlockId::Hash(hash) => hash,
+            BlockId::Number(number) => self
+                .block_hash(target)
+                .ok_or(ErrorKind::UnavailableBlock(number))?,
+            id => self
+                .block_hash(id)
+                .expect("the earliest and latest blocks are always known; qed"),
+        };
+        let number = self
+            .store
+            .header(&hash)
+            .ok_or(ErrorKind::NotCanonical(hash))?
+            .number();
+
+        let boundary = self.finalized.unwrap_or(0).max(self.earliest_chain());
+        if number < boundary {
+            return Err(ErrorKind::RevertBelowBoundary(number, boundary).into());
+        }
+        if !self.is_canonical(&hash) {
+            return Err(ErrorKind::NotCanonical(hash).into());
+        }
+
+        let retracted: Vec<H256> = (number + 1..=self.best_block.number)
+            .rev()
+            .map(|n| {
+                self.canonical_hash(n)
+                    .expect("every block up to the best block has an entry; qed")
+            })
+            .collect();
+        if retracted.is_empty() {
+            return Ok(retracted);
+        }
+
+        let mut batch = Batch::default();
+        let mut removed: HashSet<H256> = retracted.iter().cloned().collect();
+        let mut height = number + 1;
+        while let Some(mut entry) = self.store.entry(height) {
+            let (dropped, kept): (Vec<_>, Vec<_>) = entry
+                .candidates
+                .into_iter()
+                .partition(|c| removed.contains(&c.hash) || removed.contains(&c.parent_hash));
+            for candidate in dropped {
+                batch.remove_header(candidate.hash);
+                removed.insert(candidate.hash);
+            }
+
+            // entries above the best block are never canonical, so any
+            // remaining candidate will do.
+            match kept.first().map(|c| c.hash) {
+                Some(canonical_hash) => {
+                    entry.candidates = kept;
+                    entry.canonical_hash = canonical_hash;
+                    batch.insert_entry(height, entry);
+                }
+                None => batch.remove_entry(height),
+            }
+            height += 1;
+        }
+        batch.set_best_block(hash);
+        self.store.write(batch)?;
+
+        self.best_block = load_descriptor(&*self.store, hash)?;
+        for notify in &self.notify {
+            notify.retracted(&retracted);
+        }
+
+        Ok(retracted)
+    }
+
+    /// Set the maximum number of blocks a reorganization may retract, or
+    /// `None` for no limit.
+    ///
+    /// A heavier fork which would retract more blocks is quarantined rather
+    /// than adopted, and inserting it fails with `ErrorKind::ReorgTooDeep`.
+    /// So do any of its descendants.
+    pub fn set_max_reorg_depth(&mut self, depth: Option<u64>) {
+        self.max_reorg_depth = depth;
+    }
+
+    /// Whether the header with the given hash was quarantined.
+    pub fn is_quarantined(&self, hash: &H256) -> bool {
+        self.quarantine.headers.contains_key(hash)
+    }
+
+    /// Remove and return all quarantined headers, oldest first.
+    pub fn take_quarantined(&mut self) -> Vec<Header> {
+        let Quarantine { mut headers, order } = mem::take(&mut self.quarantine);
+        order
+            .into_iter()
+            .filter_map(|hash| headers.remove(&hash))
+            .map(|(header, _)| header)
+            .collect()
+    }
+
+    /// Set the rule used to decide whether a new header replaces the best
+    /// block. Defaults to `TotalDifficulty`.
+    pub fn set_fork_choice(&mut self, fork_choice: Box<dyn ForkChoiceRule<ExtendedHeader>>) {
+        self.fork_choice = fork_choice;
+    }
+
+    /// Register a subscriber for chain notifications.
+    pub fn add_notify(&mut self, notify: Arc<dyn ChainNotify>) {
+        self.notify.push(notify);
+    }
+
+    /// Import a header delivered by `sender`.
+    ///
+    /// Unlike `insert`, the header is first run through the engine's basic
+    /// and unordered verification stages, so it may come straight from an
+    /// untrusted source. A header whose parent is unknown is then kept in the
+    /// orphan pool and inserted automatically once its parent is imported.
+    /// Orphans which turn out to be invalid are dropped along with their
+    /// descendants. `BlockError::UnknownParent` is only returned if the pool
+    /// refuses the header, or if its parent can never be imported.
+    pub fn import(&mut self, header: Header, sender: SenderId) -> Result<ImportOutcome, Error> {
+        let hash = header.hash();
+        let parent_hash = *header.parent_hash();
+
+        self.engine.verify_block_basic(&header)?;
+        self.engine.verify_block_unordered(&header)?;
+
+        if !self.is_known(&parent_hash) && !self.is_quarantined(&parent_hash) {
+            if self.orphans.contains(&hash)
+                || (header.number() > self.earliest_chain() && self.orphans.insert(header, sender))
+            {
+                return Ok(ImportOutcome::Queued);
+            }
+            return Err(BlockError::UnknownParent(parent_hash).into());
+        }
+
+        let old_best = self.best_block.hash;
+        self.insert(header)?;
+
+        let mut headers = vec![hash];
+        let mut parents = vec![hash];
+        while let Some(parent_hash) = parents.pop() {
+            for (orphan, _) in self.orphans.take_children(&parent_hash) {
+                let orphan_hash = orphan.hash();
+                if self.insert(orphan).is_ok() {
+                    headers.push(orphan_hash);
+                    parents.push(orphan_hash);
+                    continue;
+                }
+
+                let mut invalid = vec![orphan_hash];
+                while let Some(invalid_hash) = invalid.pop() {
+                    invalid.extend(
+                        self.orphans
+                            .take_children(&invalid_hash)
+                            .into_iter()
+                            .map(|(orphan, _)| orphan.hash()),
+                    );
+                }
+            }
+        }
+
+        let route = if self.best_block.hash != old_best {
+            self.tree_route(old_best, self.best_block.hash)
+        } else {
+            None
+        };
+        Ok(ImportOutcome::Imported { headers, route })
+    }
+
+    /// Insert every header the queue has finished verifying, in the order
+    /// they were queued.
+    ///
+    /// Descendants of a bad header fail with `BlockError::UnknownParent`.
+    pub fn import_verified(&mut self, queue: &VerificationQueue) -> ImportedHeaders {
+        let mut result = ImportedHeaders::default();
+        for (hash, verified) in queue.drain() {
+            match verified.and_then(|header| self.insert(header)) {
+                Ok(_) => result.imported.push(hash),
+                Err(err) => result.bad.push((hash, err)),
+            }
+        }
+        result
+    }
+
+    /// Get the pool of headers waiting for their parent.
+    pub fn orphans(&self) -> &OrphanPool {
+        &self.orphans
+    }
+
+    /// Replace the orphan pool with an empty one using the given limits.
+    pub fn set_orphan_limits(&mut self, max_per_sender: usize, max_memory: usize) {
+        self.orphans = OrphanPool::new(max_per_sender, max_memory);
+    }
+
+    /// Set the pruning policy, pruning right away if it allows it.
+    ///
+    /// The policy is not persisted; a resumed chain keeps every header until
+    /// a policy is set again.
+    pub fn set_pruning(&mut self, pruning: Pruning) -> Result<(), Error> {
+        self.pruning = pruning;
+        self.prune()
+    }
+
+    /// Get the current pruning policy.
+    pub fn pruning(&self) -> Pruning {
+        self.pruning
+    }
+
+    /// Get information about which blocks can be served in full.
+    ///
+    /// The light client holds no state, so no state requests can be served.
+    pub fn pruning_info(&self) -> PruningInfo {
+        PruningInfo {
+            earliest_chain: self.earliest_chain(),
+            earliest_state: u64::MAX,
+        }
+    }
+
+    /// Discard every complete CHT range which lies entirely behind the
+    /// history kept by the pruning policy, recording the CHT root instead.
+    /// The store is compacted afterwards, so the space is reclaimed on disk
+    /// as well.
+    fn prune(&mut self) -> Result<(), Error> {
+        let history = match self.pruning {
+            Pruning::Archive => return Ok(()),
+            Pruning::KeepRecent(history) => history,
+        };
+
+        let mut pruned = false;
+        loop {
+            let start = cht::start_number(self.next_cht);
+            let end = start + cht::SIZE - 1;
+            if end.saturating_add(history) >= self.best_block.number {
+                break;
+            }
+
+            let root = self
+                .build_cht(self.next_cht)
+                .ok_or_else(|| corrupt_store("canonical header is missing"))?
+                .root();
+
+            let mut batch = Batch::default();
+            let mut removed = HashSet::new();
+            for number in start..=end {
+                if let Some(entry) = self.store.entry(number) {
+                    for candidate in entry.candidates {
+                        batch.remove_header(candidate.hash);
+                        removed.insert(candidate.hash);
+                    }
+                    batch.remove_entry(number);
+                }
+            }
+
+            // forks branching off inside the pruned range can never be
+            // routed to again, so drop them as well, including the parts
+            // which reach past the best block. No block past the best block
+            // is canonical, whatever its entry names.
+            let best_number = self.best_block.number;
+            let mut number = end + 1;
+            while !removed.is_empty() {
+                let mut entry = match self.store.entry(number) {
+                    Some(entry) => entry,
+                    None => break,
+                };
+                let canonical_hash = entry.canonical_hash;
+                let (dangling, kept): (Vec<_>, Vec<_>) =
+                    entry.candidates.into_iter().partition(|c| {
+                        (number > best_number || c.hash != canonical_hash)
+                            && removed.contains(&c.parent_hash)
+                    });
+
+                removed.clear();
+                if !dangling.is_empty() {
+                    for candidate in dangling {
+                        batch.remove_header(candidate.hash);
+                        removed.insert(candidate.hash);
+                    }
+                    match kept.first() {
+                        None => batch.remove_entry(number),
+                        Some(first) => {
+                            if !kept.iter().any(|c| c.hash == canonical_hash) {
+                                entry.canonical_hash = first.hash;
+                            }
+                            entry.candidates = kept;
+                            batch.insert_entry(number, entry);
+                        }
+                    }
+                }
+                number += 1;
+            }
+
+            batch.insert_cht_root(self.next_cht, root);
+            self.store.write(batch)?;
+            self.next_cht += 1;
+            pruned = true;
+        }
+
+        if pruned {
+            self.store.compact()?;
+        }
+        Ok(())
+    }
+
+    /// The first block from which the canonical chain is kept in full.
+    fn earliest_chain(&self) -> BlockNumber {
+        if self.next_cht == first_prunable_cht(self.first_block.number) {
+            self.first_block.number
+        } else {
+            cht::start_number(self.next_cht)
+        }
+    }
+
+    /// Get the route between two known blocks.
+    ///
+    /// The blocks in the route are ordered from `from` to `to`, excluding the
+    /// common ancestor. Returns `None` if either block is unknown.
+    pub fn tree_route(&self, from: H256, to: H256) -> Option<TreeRoute> {
+        let mut from_branch = Vec::new();
+        let mut to_branch = Vec::new();
+
+        let mut current_from = from;
+        let mut current_to = to;
+        let (mut from_number, mut from_parent) = self.number_and_parent(&current_from)?;
+        let (mut to_number, mut to_parent) = self.number_and_parent(&current_to)?;
+
+        // reset from && to to the same level
+        while from_number > to_number {
+            from_branch.push(current_from);
+            current_from = from_parent;
+            let next = self.number_and_parent(&current_from)?;
+            from_number = next.0;
+            from_parent = next.1;
+        }
+
+        while to_number > from_number {
+            to_branch.push(current_to);
+            current_to = to_parent;
+            let next = self.number_and_parent(&current_to)?;
+            to_number = next.0;
+            to_parent = next.1;
+        }
+
+        // move to shared parent
+        while current_from != current_to {
+            from_branch.push(current_from);
+            current_from = from_parent;
+            from_parent = self.number_and_parent(&current_from)?.1;
+
+            to_branch.push(current_to);
+            current_to = to_parent;
+            to_parent = self.number_and_parent(&current_to)?.1;
+        }
+
+        // finalized blocks are canonical, so if any block on the `from`
+        // branch is finalized, the one closest to the ancestor is.
+        let index = from_branch.len();
+        let is_from_route_finalized = index > 0 && self.is_finalized(&from_branch[index - 1]);
+        from_branch.extend(to_branch.into_iter().rev());
+
+        Some(TreeRoute {
+            blocks: from_branch,
+            ancestor: current_from,
+            index,
+            is_from_route_finalized,
+        })
+    }
+
+    fn number_and_parent(&self, hash: &H256) -> Option<(BlockNumber, H256)> {
+        self.store
+            .header(hash)
+            .map(|header| (header.number(), header.parent_hash()))
+    }
+
+    /// Mark `hash` and its ancestors canonical in `batch`, stopping at the
+    /// first ancestor which already is canonical under the current best block.
+    fn set_canonical(&self, batch: &mut Batch, hash: H256, number: BlockNumber) {
+        let best_number = self.best_block.number;
+        let mut canon_hash = hash;
+        let mut canon_number = number;
+
+        loop {
+            let mut entry = self
+                .store
+                .entry(canon_number)
+                .expect("every ancestor of an inserted header has an entry; qed");
+            if canon_number <= best_number && entry.canonical_hash == canon_hash {
+                break;
+            }
+
+            let parent_hash = entry
+                .candidates
+                .iter()
+                .find(|c| c.hash == canon_hash)
+                .expect("canonical hash is always one of the entry's candidates; qed")
+                .parent_hash;
+            entry.canonical_hash = canon_hash;
+            batch.insert_entry(canon_number, entry);
+
+            if canon_number == 0 {
+                break;
+            }
+            canon_hash = parent_hash;
+            canon_number -= 1;
+        }
+    }
+
+    /// Get the engine verifying headers.
+    pub fn engine(&self) -> &Arc<E> {
+        &self.engine
+    }
+
+    /// Get the genesis hash.
+    pub fn genesis_hash(&self) -> H256 {
+        self.genesis_header.hash()
+    }
+
+    /// Get the genesis header.
+    pub fn genesis_header(&self) -> encoded::Header {
+        self.genesis_header.clone()
+    }
+
+    /// Get the best block's descriptor.
+    pub fn best_block(&self) -> BlockDescriptor {
+        self.best_block.clone()
+    }
+
+    /// Get the descriptor of the block the chain was started from: either
+    /// the genesis block or a hardcoded checkpoint.
+    pub fn first_block(&self) -> BlockDescriptor {
+        self.first_block.clone()
+    }
+
+    /// Get a summary of the chain.
+    ///
+    /// For a chain started from a checkpoint, genesis is reported as the
+    /// ancient block and the checkpoint as the first block, so that only the
+    /// headers after the checkpoint count towards the security level.
+    pub fn chain_info(&self) -> BlockChainInfo {
+        let best_header = self
+            .store
+            .header(&self.best_block.hash)
+            .expect("best block header is always stored; qed");
+        let (ancient_block, first_block) = if self.first_block.hash == self.genesis_hash() {
+            (None, None)
+        } else {
+            (
+                Some((self.genesis_hash(), 0)),
+                Some((self.first_block.hash, self.first_block.number)),
+            )
+        };
+
+        BlockChainInfo {
+            total_difficulty: self.best_block.total_difficulty,
+            pending_total_difficulty: self.best_block.total_difficulty,
+            genesis_hash: self.genesis_hash(),
+            best_block_hash: self.best_block.hash,
+            best_block_number: self.best_block.number,
+            best_block_timestamp: best_header.timestamp(),
+            ancient_block_hash: ancient_block.map(|(hash, _)| hash),
+            ancient_block_number: ancient_block.map(|(_, number)| number),
+            first_block_hash: first_block.map(|(hash, _)| hash),
+            first_block_number: first_block.map(|(_, number)| number),
+        }
+    }
+
+    /// Resolve a block id to the hash of a known block.
+    ///
+    /// Numbers resolve through the canonical index, so only canonical blocks
+    /// which have not been pruned are found by number.
+    pub fn block_hash(&self, id: BlockId) -> Option<H256> {
+        match id {
+            BlockId::Hash(hash) => Some(hash).filter(|hash| self.is_known(hash)),
+            BlockId::Number(0) | BlockId::Earliest => Some(self.genesis_hash()),
+            BlockId::Number(number) => self.canonical_hash(number),
+            BlockId::Latest => Some(self.best_block.hash),
+        }
+    }
+
+    /// Get the header of a known block by id.
+    pub fn header(&self, id: BlockId) -> Option<encoded::Header> {
+        match id {
+            BlockId::Number(0) | BlockId::Earliest => Some(self.genesis_header()),
+            id => self.store.header(&self.block_hash(id)?),
+        }
+    }
+
+    /// Get a header by hash, whether canonical or not.
+    pub fn block_header(&self, hash: &H256) -> Option<encoded::Header> {
+        self.store.header(hash)
+    }
+
+    /// Get the total difficulty of a known header.
+    pub fn total_difficulty(&self, hash: &H256) -> Option<U256> {
+        self.store.total_difficulty(hash)
+    }
+
+    /// Whether the header with the given hash is known.
+    pub fn is_known(&self, hash: &H256) -> bool {
+        self.store.header(hash).is_some()
+    }
+
+    /// Get the number of candidate headers at the given height.
+    pub fn candidates_at(&self, number: BlockNumber) -> usize {
+        self.store
+            .entry(number)
+            .map_or(0, |entry| entry.candidates.len())
+    }
+
+    /// Set how deep below the best block a canonical block must be to be
+    /// finalized, or `None` to only finalize blocks the engine considers
+    /// final.
+    ///
+    /// A heavier fork which would retract a finalized block is kept as a
+    /// candidate, but never becomes canonical.
+    pub fn set_finality_depth(&mut self, depth: Option<u64>) {
+        self.finality_depth = depth;
+        if depth.is_none() {
+            self.finalized = None;
+        }
+        self.update_finality();
+    }
+
+    /// Get the number of the most recent finalized block.
+    ///
+    /// Blocks stay finalized even if the best block later moves to a shorter,
+    /// heavier fork.
+    pub fn finalized_number(&self) -> Option<BlockNumber> {
+        self.finalized
+    }
+
+    /// Advance the finalized block according to the finality depth and tell
+    /// subscribers about it.
+    fn update_finality(&mut self) {
+        let depth = match self.finality_depth {
+            Some(depth) => depth,
+            None => return,
+        };
+        self.finalize(self.best_block.number.saturating_sub(depth));
+    }
+
+    /// Finalize the canonical block at the given height along with its
+    /// ancestors, unless a later block is finalized already. Subscribers hear
+    /// of every newly finalized block, parents first.
+    fn finalize(&mut self, number: BlockNumber) {
+        let first = match self.finalized {
+            Some(finalized) if finalized >= number => return,
+            Some(finalized) => finalized + 1,
+            None => self.earliest_chain(),
+        };
+
+        self.finalized = Some(number);
+        for hash in (first..=number).filter_map(|n| self.canonical_hash(n)) {
+            for notify in &self.notify {
+                notify.finalized(hash);
+            }
+        }
+    }
+
+    /// Whether the block with the given hash is known and canonical.
+    pub fn is_canonical(&self, hash: &H256) -> bool {
+        self.store
+            .header(hash)
+            .is_some_and(|header| self.canonical_hash(header.number()) == Some(*hash))
+    }
+
+    /// Whether the block with the given hash is known, canonical and
+    /// finalized.
+    pub fn is_finalized(&self, hash: &H256) -> bool {
+        match (self.store.header(hash), self.finalized_number()) {
+            (Some(header), Some(finalized)) => {
+                header.number() <= finalized && self.is_canonical(hash)
+            }
+            _ => false,
+        }
+    }
+
+    /// Get the number of confirmations of a canonical block, counting the
+    /// block itself: the best block has one confirmation.
+    ///
+    /// Returns `None` if the block is unknown or not canonical.
+    pub fn confirmations(&self, hash: &H256) -> Option<u64> {
+        let number = self.store.header(hash)?.number();
+        if self.canonical_hash(number) != Some(*hash) {
+            return None;
+        }
+        Some(self.best_block.number - number + 1)
+    }
+
+    /// Whether the block with the given hash is canonical and has at least
+    /// `k` confirmations.
+    pub fn is_confirmed(&self, hash: &H256, k: u64) -> bool {
+        self.confirmations(hash)
+            .is_some_and(|confirmations| confirmations >= k)
+    }
+
+    /// Get a known header along with its parent's total difficulty and
+    /// finality, either by the chain's finalized block or by the engine.
+    pub fn extended_header(&self, hash: &H256) -> Option<ExtendedHeader> {
+        let header = self
+            .store
+            .header(hash)?
+            .decode()
+            .expect("stored headers are always valid; qed");
+        let total_difficulty = self.store.total_difficulty(hash)?;
+
+        Some(ExtendedHeader {
+            parent_total_difficulty: total_difficulty - *header.difficulty(),
+            is_finalized: self.is_finalized(hash) || self.engine.is_finalized(&header),
+            header,
+        })
+    }
+
+    /// Get the descriptor of the canonical block at the given height, if it
+    /// has not been pruned.
+    pub fn canonical_block(&self, number: BlockNumber) -> Option<BlockDescriptor> {
+        let hash = self.canonical_hash(number)?;
+        Some(BlockDescriptor {
+            hash,
+            number,
+            total_difficulty: self.store.total_difficulty(&hash)?,
+        })
+    }
+
+    /// Get the descriptor of the canonical block at the given height, using a
+    /// CHT inclusion proof if the block has been pruned or precedes the
+    /// checkpoint the chain was started from.
+    ///
+    /// Returns `None` if the block is unknown and the proof does not check out
+    /// against a known CHT root.
+    pub fn canonical_block_with_proof(
+        &self,
+        number: BlockNumber,
+        proof: &[Bytes],
+    ) -> Option<BlockDescriptor> {
+        if let Some(block) = self.canonical_block(number) {
+            return Some(block);
+        }
+
+        let root = self.cht_root(cht::block_to_cht_number(number)?)?;
+        let (hash, total_difficulty) = cht::check_proof(proof, number, root)?;
+        Some(BlockDescriptor {
+            hash,
+            number,
+            total_difficulty,
+        })
+    }
+
+    /// Get the root of the CHT with the given index, if one has been stored.
+    pub fn cht_root(&self, index: u64) -> Option<H256> {
+        self.store.cht_root(index)
+    }
+
+    /// Build the CHT with the given index from the canonical chain.
+    ///
+    /// Returns `None` unless every block covered by the CHT is known and
+    /// canonical.
+    pub fn build_cht(&self, index: u64) -> Option<cht::CHT> {
+        cht::build(index, |id| {
+            let hash = self.block_hash(id)?;
+            let header = self.store.header(&hash)?;
+
+            Some(cht::BlockInfo {
+                hash,
+                parent_hash: header.parent_hash(),
+                total_difficulty: self.store.total_difficulty(&hash)?,
+            })
+        })
+    }
+
+    /// Get the canonical hash at the given height. Entries above the best
+    /// block may be left over from a retracted fork, so they are ignored.
+    fn canonical_hash(&self, number: BlockNumber) -> Option<H256> {
+        if number > self.best_block.number {
+            return None;
+        }
+        self.store.canonical_hash(number)
+    }
+}
+
+fn load_descriptor(store: &dyn HeaderStore, hash: H256) -> Result<BlockDescriptor, Error> {
+    let number = store
+        .header(&hash)
+        .map(|header| header.number())
+        .ok_or_else(|| corrupt_store("stored block header is missing"))?;
+    let total_difficulty = store
+        .total_difficulty(&hash)
+        .ok_or_else(|| corrupt_store("stored block entry is missing"))?;
+
+    Ok(BlockDescriptor {
+        hash,
+        number,
+        total_difficulty,
+    })
+}
+
+/// The index of the first CHT which only covers blocks after the first block.
+pub(crate) fn first_prunable_cht(first_number: BlockNumber) -> u64 {
+    first_number.div_ceil(cht::SIZE)
+}
+
+fn corrupt_store(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HardcodedSync, HeaderChain, ImportOutcome, Pruning};
+    use crate::cht;
+    use crate::engine::{Engine, NullEngine};
+    use crate::error::{BlockError, Error, ErrorKind};
+    use crate::fork_choice::{ForkChoice, ForkChoiceRule, ProofOfAuthority};
+    use crate::header::ExtendedHeader;
+    use crate::header::Header;
+    use crate::header_store::{Batch, FileStore, MemoryStore};
+    use crate::notify::{ChainEvent, ChannelNotify};
+    use crate::test_helpers::{child, genesis};
+    use crate::verification_queue::{self, VerificationQueue};
+    use ethereum_types::{H256, U256};
+    use std::fs;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+    use types::ids::BlockId;
+    use types::security_level::SecurityLevel;
+
+    #[test]
+    fn basic_chain() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let mut parent = genesis.clone();
+        for _ in 0..10 {
+            let header = child(&parent, 100);
+            chain.insert(header.clone()).unwrap();
+            parent = header;
+        }
+
+        let best = chain.best_block();
+        assert_eq!(best.number, 10);
+        assert_eq!(best.hash, parent.hash());
+        assert_eq!(best.total_difficulty, U256::from(2000));
+        assert_eq!(
+            chain.total_difficulty(&parent.hash()),
+            Some(U256::from(2000))
+        );
+        assert_eq!(chain.genesis_hash(), genesis.hash());
+    }
+
+    #[test]
+    fn rejects_unknown_parent() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let orphan = child(&child(&genesis, 100), 100);
+        match chain.insert(orphan.clone()) {
+            Err(Error(ErrorKind::Block(BlockError::UnknownParent(hash)), _)) => {
+                assert_eq!(hash, *orphan.parent_hash())
+            }
+            other => panic!("unexpected import result: {:?}", other),
+        }
+        assert!(!chain.is_known(&orphan.hash()));
+    }
+
+    #[test]
+    fn reorganizes_to_heavier_fork() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let mut light = genesis.clone();
+        for _ in 0..5 {
+            light = child(&light, 100);
+            chain.insert(light.clone()).unwrap();
+        }
+        assert_eq!(chain.best_block().hash, light.hash());
+
+        let mut heavy = genesis.clone();
+        for _ in 0..3 {
+            heavy = child(&heavy, 150);
+            chain.insert(heavy.clone()).unwrap();
+        }
+        assert_eq!(chain.best_block().hash, light.hash());
+        assert_eq!(chain.candidates_at(3), 2);
+
+        heavy = child(&heavy, 150);
+        chain.insert(heavy.clone()).unwrap();
+
+        let best = chain.best_block();
+        assert_eq!(best.hash, heavy.hash());
+        assert_eq!(best.number, 4);
+        assert_eq!(best.total_difficulty, U256::from(1600));
+    }
+
+    #[test]
+    fn resolves_block_ids() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let mut light = Vec::new();
+        let mut parent = genesis.clone();
+        for _ in 0..5 {
+            parent = child(&parent, 100);
+            chain.insert(parent.clone()).unwrap();
+            light.push(parent.clone());
+        }
+        assert_eq!(chain.block_hash(BlockId::Number(5)), Some(light[4].hash()));
+        assert_eq!(chain.block_hash(BlockId::Latest), Some(light[4].hash()));
+        assert_eq!(chain.block_hash(BlockId::Earliest), Some(genesis.hash()));
+        assert_eq!(chain.header(BlockId::Number(0)), Some(genesis.encoded()));
+        assert_eq!(chain.header(BlockId::Number(3)), Some(light[2].encoded()));
+        assert_eq!(chain.block_hash(BlockId::Number(6)), None);
+        assert_eq!(chain.block_hash(BlockId::Hash(H256::zero())), None);
+
+        // reorganize onto a shorter but heavier fork branching off block 1.
+        let mut heavy = Vec::new();
+        let mut parent = light[0].clone();
+        for _ in 0..3 {
+            parent = child(&parent, 500);
+            chain.insert(parent.clone()).unwrap();
+            heavy.push(parent.clone());
+        }
+        assert_eq!(chain.best_block().number, 4);
+
+        assert_eq!(chain.block_hash(BlockId::Number(1)), Some(light[0].hash()));
+        for (index, header) in heavy.iter().enumerate() {
+            let number = index as u64 + 2;
+            assert_eq!(
+                chain.block_hash(BlockId::Number(number)),
+                Some(header.hash())
+            );
+            assert_eq!(
+                chain.header(BlockId::Number(number)),
+                Some(header.encoded())
+            );
+        }
+        // stale entries above the new best block are not resolved.
+        assert_eq!(chain.block_hash(BlockId::Number(5)), None);
+        // retracted blocks are still known by hash.
+        assert_eq!(
+            chain.header(BlockId::Hash(light[4].hash())),
+            Some(light[4].encoded())
+        );
+    }
+
+    #[test]
+    fn equal_difficulty_keeps_first_seen() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let first = child(&genesis, 100);
+        let mut second = child(&genesis, 100);
+        second.set_timestamp(first.timestamp() + 1);
+
+        chain.insert(first.clone()).unwrap();
+        chain.insert(second.clone()).unwrap();
+
+        assert_eq!(chain.best_block().hash, first.hash());
+        assert_eq!(chain.candidates_at(1), 2);
+    }
+
+    #[test]
+    fn reorg_returns_tree_route() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let a1 = child(&genesis, 100);
+        let a2 = child(&a1, 100);
+        let a3 = child(&a2, 100);
+        for header in &[&a1, &a2, &a3] {
+            chain.insert((*header).clone()).unwrap();
+        }
+
+        let b2 = child(&a1, 120);
+        let b3 = child(&b2, 120);
+        assert!(chain.insert(b2.clone()).unwrap().is_none());
+
+        let route = chain
+            .insert(b3.clone())
+            .unwrap()
+            .expect("best block changed");
+        assert_eq!(route.ancestor, a1.hash());
+        assert_eq!(route.index, 2);
+        assert_eq!(
+            route.blocks,
+            vec![a3.hash(), a2.hash(), b2.hash(), b3.hash()]
+        );
+        assert!(!route.is_from_route_finalized);
+    }
+
+    #[test]
+    fn extending_best_returns_enacted_block() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let header = child(&genesis, 100);
+        let route = chain
+            .insert(header.clone())
+            .unwrap()
+            .expect("best block changed");
+        assert_eq!(route.ancestor, genesis.hash());
+        assert_eq!(route.index, 0);
+        assert_eq!(route.blocks, vec![header.hash()]);
+    }
+
+    #[test]
+    fn tree_route_between_forks() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let a1 = child(&genesis, 100);
+        let a2 = child(&a1, 100);
+        let b1 = child(&genesis, 90);
+        for header in &[&a1, &a2, &b1] {
+            chain.insert((*header).clone()).unwrap();
+        }
+
+        let route = chain.tree_route(b1.hash(), a2.hash()).unwrap();
+        assert_eq!(route.ancestor, genesis.hash());
+        assert_eq!(route.index, 1);
+        assert_eq!(route.blocks, vec![b1.hash(), a1.hash(), a2.hash()]);
+
+        let route = chain.tree_route(a2.hash(), a2.hash()).unwrap();
+        assert_eq!(route.ancestor, a2.hash());
+        assert!(route.blocks.is_empty());
+
+        assert!(chain.tree_route(a2.hash(), child(&a2, 1).hash()).is_none());
+    }
+
+    #[test]
+    fn resumes_from_file_store() {
+        let tempdir = TempDir::new("header-chain").unwrap();
+        let path = tempdir.path().join("headers");
+        let genesis = genesis();
+
+        let mut light = genesis.clone();
+        let mut heavy = genesis.clone();
+        {
+            let store = FileStore::open(&path).unwrap();
+            let mut chain =
+                HeaderChain::with_store(Arc::new(NullEngine), genesis.encoded(), Box::new(store))
+                    .unwrap();
+            for _ in 0..3 {
+                light = child(&light, 100);
+                chain.insert(light.clone()).unwrap();
+            }
+            heavy = child(&heavy, 120);
+            chain.insert(heavy.clone()).unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        let mut chain =
+            HeaderChain::with_store(Arc::new(NullEngine), genesis.encoded(), Box::new(store))
+                .unwrap();
+        assert_eq!(chain.best_block().hash, light.hash());
+        assert_eq!(chain.best_block().total_difficulty, U256::from(1300));
+
+        // the side fork survived the restart and can still take over.
+        heavy = child(&heavy, 120);
+        chain.insert(heavy.clone()).unwrap();
+        heavy = child(&heavy, 120);
+        let route = chain.insert(heavy.clone()).unwrap().unwrap();
+        assert_eq!(chain.best_block().hash, heavy.hash());
+        assert_eq!(route.index, 3);
+    }
+
+    fn checkpoint() -> (Header, HardcodedSync) {
+        let mut header = Header::new();
+        header.set_number(5000);
+        header.set_difficulty(U256::from(1000));
+
+        let checkpoint = HardcodedSync {
+            header: header.encoded(),
+            total_difficulty: U256::from(5_000_000),
+            chts: vec![H256::from_slice(&[1; 32]), H256::from_slice(&[2; 32])],
+        };
+        (header, checkpoint)
+    }
+
+    #[test]
+    fn starts_from_checkpoint() {
+        let genesis = genesis();
+        let (checkpoint_header, checkpoint) = checkpoint();
+        let mut chain = HeaderChain::with_checkpoint(
+            Arc::new(NullEngine),
+            genesis.encoded(),
+            checkpoint,
+            Box::new(MemoryStore::new()),
+        )
+        .unwrap();
+
+        let best = chain.best_block();
+        assert_eq!(best.hash, checkpoint_header.hash());
+        assert_eq!(best.number, 5000);
+        assert_eq!(best.total_difficulty, U256::from(5_000_000));
+        assert_eq!(chain.first_block(), best);
+        assert_eq!(chain.block_header(&genesis.hash()), Some(genesis.encoded()));
+        assert_eq!(chain.cht_root(1), Some(H256::from_slice(&[2; 32])));
+        assert_eq!(chain.cht_root(2), None);
+        assert_eq!(chain.pruning_info().earliest_chain, 5000);
+
+        let header = child(&checkpoint_header, 100);
+        chain.insert(header.clone()).unwrap();
+        assert_eq!(chain.best_block().hash, header.hash());
+        assert_eq!(chain.best_block().total_difficulty, U256::from(5_000_100));
+
+        // nothing before the checkpoint can be imported.
+        assert!(chain.insert(child(&genesis, 100)).is_err());
+    }
+
+    #[test]
+    fn checkpoint_is_ignored_when_resuming() {
+        let tempdir = TempDir::new("header-chain").unwrap();
+        let path = tempdir.path().join("headers");
+        let genesis = genesis();
+        let (checkpoint_header, checkpoint) = checkpoint();
+
+        let header = child(&checkpoint_header, 100);
+        {
+            let store = FileStore::open(&path).unwrap();
+            let mut chain = HeaderChain::with_checkpoint(
+                Arc::new(NullEngine),
+                genesis.encoded(),
+                checkpoint.clone(),
+                Box::new(store),
+            )
+            .unwrap();
+            chain.insert(header.clone()).unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        let chain = HeaderChain::with_checkpoint(
+            Arc::new(NullEngine),
+            genesis.encoded(),
+            checkpoint,
+            Box::new(store),
+        )
+        .unwrap();
+        assert_eq!(chain.best_block().hash, header.hash());
+        assert_eq!(chain.first_block().hash, checkpoint_header.hash());
+    }
+
+    #[test]
+    fn hardcoded_sync_from_json() {
+        let json = ethjson::spec::HardcodedSync {
+            header: "f901f9a0d405da4e66f1445d455195229624e133f5baafe72b5cf7b3c36c12c8146e98b7a01dcc4de8dec75d7aab85b567b6ccd41ad312451b948a7413f0a142fd40d49347948888f1f195afa192cfee860698584c030f4c9db1a05fb2b4bfdef7b314451cb138a534d225c922fc0e5fbe25e451142732c3e25c25a088d2ec6b9860aae1a2c3b299f72b6a5d70d7f7ba4722c78f2c49ba96273c2158a007c6fdfa8eea7e86b81f5b0fc0f78f90cc19f4aa60d323151e0cac660199e9a1b90100000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000008302008003832fefba82524d84568e932a80a0a0349d8c3df71f1a48a9df7d03fd5f14aeee7d91332c009ecaff0a71ead405bd88ab4e252a7e8c2a23".into(),
+            total_difficulty: ethjson::uint::Uint(U256::from(0x400000000u64)),
+            chts: vec![ethjson::hash::H256(H256::from_slice(&[1; 32]))],
+        };
+
+        let checkpoint = HardcodedSync::from_json(json).unwrap();
+        assert_eq!(checkpoint.header.number(), 3);
+        assert_eq!(checkpoint.total_difficulty, U256::from(0x400000000u64));
+        assert_eq!(checkpoint.chts, vec![H256::from_slice(&[1; 32])]);
+
+        let invalid = ethjson::spec::HardcodedSync {
+            header: "zz".into(),
+            total_difficulty: ethjson::uint::Uint(U256::zero()),
+            chts: Vec::new(),
+        };
+        assert!(HardcodedSync::from_json(invalid).is_err());
+    }
+
+    #[test]
+    fn duplicate_insert_is_noop() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let header = child(&genesis, 100);
+        chain.insert(header.clone()).unwrap();
+        chain.insert(header.clone()).unwrap();
+
+        assert_eq!(chain.candidates_at(1), 1);
+        assert_eq!(chain.best_block().total_difficulty, U256::from(1100));
+    }
+
+    #[test]
+    fn builds_cht_from_canonical_chain() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let mut parent = genesis.clone();
+        let mut hashes = Vec::new();
+        for _ in 0..cht::SIZE {
+            let header = child(&parent, 100);
+            chain.insert(header.clone()).unwrap();
+            hashes.push(header.hash());
+            parent = header;
+        }
+
+        let cht = chain.build_cht(0).unwrap();
+        assert!(chain.build_cht(1).is_none());
+
+        let expected = cht::compute_root(
+            0,
+            hashes
+                .iter()
+                .enumerate()
+                .map(|(i, hash)| (*hash, U256::from(1000 + 100 * (i as u64 + 1)))),
+        );
+        assert_eq!(Some(cht.root()), expected);
+
+        let proof = cht.prove(42).unwrap();
+        assert_eq!(
+            cht::check_proof(&proof, 42, cht.root()),
+            Some((hashes[41], U256::from(1000 + 100 * 42)))
+        );
+    }
+
+    #[test]
+    fn prunes_old_headers_into_chts() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+        assert_eq!(chain.pruning_info().earliest_chain, 0);
+
+        let mut parent = genesis.clone();
+        let mut forks = Vec::new();
+        let mut old_header = None;
+        for number in 1..2 * cht::SIZE + 11 {
+            let header = child(&parent, 100);
+            chain.insert(header.clone()).unwrap();
+            if number == 5 {
+                old_header = Some(header.clone());
+            }
+            // lighter forks inside and right after the first CHT range.
+            if number == 100 || number == cht::SIZE {
+                let fork = child(&header, 50);
+                chain.insert(fork.clone()).unwrap();
+                forks.push(fork);
+            }
+            parent = header;
+        }
+
+        let cht = chain.build_cht(0).unwrap();
+        let old_block = chain.canonical_block(5).unwrap();
+        chain.set_pruning(Pruning::KeepRecent(10)).unwrap();
+
+        // only the first range lies entirely behind the kept history.
+        assert_eq!(chain.pruning_info().earliest_chain, cht::SIZE + 1);
+        assert_eq!(chain.cht_root(0), Some(cht.root()));
+        assert_eq!(chain.cht_root(1), None);
+        assert!(chain.canonical_block(5).is_none());
+        assert!(chain.canonical_block(cht::SIZE + 1).is_some());
+        assert!(chain.block_header(&old_block.hash).is_none());
+        assert!(chain.block_header(&genesis.hash()).is_some());
+        for fork in &forks {
+            assert!(!chain.is_known(&fork.hash()));
+        }
+
+        let proof = cht.prove(5).unwrap();
+        assert_eq!(chain.canonical_block_with_proof(5, &proof), Some(old_block));
+        assert!(chain.canonical_block_with_proof(6, &proof).is_none());
+
+        match chain.insert(child(&old_header.unwrap(), 100)) {
+            Err(Error(ErrorKind::Block(BlockError::UnknownParent(_)), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        // the next range is pruned once the best block moves far enough.
+        for _ in 0..cht::SIZE {
+            let header = child(&parent, 100);
+            chain.insert(header.clone()).unwrap();
+            parent = header;
+        }
+        assert_eq!(chain.pruning_info().earliest_chain, 2 * cht::SIZE + 1);
+        assert!(chain.cht_root(1).is_some());
+    }
+
+    #[test]
+    fn prunes_forks_taller_than_best() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let mut parent = genesis.clone();
+        let mut fork_base = None;
+        for number in 1..=cht::SIZE + 20 {
+            let header = child(&parent, 100);
+            chain.insert(header.clone()).unwrap();
+            if number == 100 {
+                fork_base = Some(header.clone());
+            }
+            parent = header;
+        }
+
+        // a long, light fork off the first CHT range which ends above the
+        // best block.
+        let mut fork = fork_base.unwrap();
+        while fork.number() < cht::SIZE + 30 {
+            let header = child(&fork, 1);
+            chain.insert(header.clone()).unwrap();
+            fork = header;
+        }
+        assert_eq!(chain.best_block().number, cht::SIZE + 20);
+
+        chain.set_pruning(Pruning::KeepRecent(10)).unwrap();
+        assert!(!chain.is_known(&fork.hash()));
+        assert_eq!(chain.candidates_at(cht::SIZE + 30), 0);
+
+        match chain.insert(child(&fork, 1_000_000)) {
+            Err(Error(ErrorKind::Block(BlockError::UnknownParent(_)), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(chain.best_block().hash, parent.hash());
+    }
+
+    #[test]
+    fn pruned_chain_resumes_from_file_store() {
+        let tempdir = TempDir::new("header-chain").unwrap();
+        let path = tempdir.path().join("headers");
+        let genesis = genesis();
+
+        {
+            let store = FileStore::open(&path).unwrap();
+            let mut chain =
+                HeaderChain::with_store(Arc::new(NullEngine), genesis.encoded(), Box::new(store))
+                    .unwrap();
+            chain.set_pruning(Pruning::KeepRecent(0)).unwrap();
+
+            let mut parent = genesis.clone();
+            let mut journal_len = 0;
+            for _ in 0..cht::SIZE + 1 {
+                journal_len = fs::metadata(&path).unwrap().len();
+                let header = child(&parent, 100);
+                chain.insert(header.clone()).unwrap();
+                parent = header;
+            }
+            assert_eq!(chain.pruning_info().earliest_chain, cht::SIZE + 1);
+            // the last insert pruned the first CHT and compacted the journal.
+            assert!(fs::metadata(&path).unwrap().len() < journal_len);
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        let chain =
+            HeaderChain::with_store(Arc::new(NullEngine), genesis.encoded(), Box::new(store))
+                .unwrap();
+        assert_eq!(chain.pruning(), Pruning::Archive);
+        assert_eq!(chain.pruning_info().earliest_chain, cht::SIZE + 1);
+        assert!(chain.cht_root(0).is_some());
+        assert!(chain.canonical_block(1).is_none());
+    }
+
+    #[test]
+    fn confirmation_queries() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let mut parent = genesis.clone();
+        let mut headers = Vec::new();
+        for _ in 0..10 {
+            let header = child(&parent, 100);
+            chain.insert(header.clone()).unwrap();
+            headers.push(header.clone());
+            parent = header;
+        }
+        let fork = child(&headers[3], 50);
+        chain.insert(fork.clone()).unwrap();
+
+        let block5 = headers[4].hash();
+        assert!(chain.is_canonical(&block5));
+        assert_eq!(chain.confirmations(&block5), Some(6));
+        assert_eq!(chain.confirmations(&headers[9].hash()), Some(1));
+        assert!(chain.is_confirmed(&block5, 6));
+        assert!(!chain.is_confirmed(&block5, 7));
+
+        assert!(!chain.is_canonical(&fork.hash()));
+        assert_eq!(chain.confirmations(&fork.hash()), None);
+        assert!(!chain.is_confirmed(&fork.hash(), 0));
+        assert!(!chain.is_canonical(&H256::zero()));
+    }
+
+    #[test]
+    fn finalized_blocks_are_never_reorganized() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let mut parent = genesis.clone();
+        let mut headers = vec![genesis.clone()];
+        for _ in 0..10 {
+            let header = child(&parent, 100);
+            chain.insert(header.clone()).unwrap();
+            headers.push(header.clone());
+            parent = header;
+        }
+
+        assert!(
+            !chain
+                .extended_header(&headers[5].hash())
+                .unwrap()
+                .is_finalized
+        );
+        chain.set_finality_depth(Some(3));
+        assert_eq!(chain.finalized_number(), Some(7));
+
+        let extended = chain.extended_header(&headers[7].hash()).unwrap();
+        assert!(extended.is_finalized);
+        assert_eq!(extended.parent_total_difficulty, U256::from(1600));
+        assert!(
+            !chain
+                .extended_header(&headers[8].hash())
+                .unwrap()
+                .is_finalized
+        );
+
+        // a heavier fork retracting block 7 is stored but not switched to.
+        let best = chain.best_block();
+        let deep_fork = child(&headers[6], 10_000);
+        assert!(chain.insert(deep_fork.clone()).unwrap().is_none());
+        assert_eq!(chain.best_block(), best);
+        assert!(chain.is_known(&deep_fork.hash()));
+        assert!(
+            chain
+                .tree_route(best.hash, deep_fork.hash())
+                .unwrap()
+                .is_from_route_finalized
+        );
+
+        // forks above the finalized block still reorganize.
+        let shallow_fork = child(&headers[7], 10_000);
+        let route = chain.insert(shallow_fork.clone()).unwrap().unwrap();
+        assert!(!route.is_from_route_finalized);
+        assert_eq!(chain.best_block().hash, shallow_fork.hash());
+    }
+
+    #[test]
+    fn imports_orphans_once_parent_arrives() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let first = child(&genesis, 100);
+        let second = child(&first, 100);
+        let third = child(&second, 100);
+        let mut invalid = child(&first, 100);
+        invalid.set_difficulty(U256::max_value());
+        let invalid_child = child(&invalid, 100);
+
+        for header in [&third, &second, &invalid, &invalid_child] {
+            match chain.import(header.clone(), 1).unwrap() {
+                ImportOutcome::Queued => {}
+                other => panic!("unexpected outcome: {:?}", other),
+            }
+        }
+        assert_eq!(chain.orphans().len(), 4);
+        assert_eq!(chain.best_block().hash, genesis.hash());
+
+        match chain.import(first.clone(), 2).unwrap() {
+            ImportOutcome::Imported { headers, route } => {
+                assert_eq!(headers, vec![first.hash(), second.hash(), third.hash()]);
+                let route = route.unwrap();
+                assert_eq!(route.ancestor, genesis.hash());
+                assert_eq!(
+                    route.blocks,
+                    vec![first.hash(), second.hash(), third.hash()]
+                );
+            }
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        assert_eq!(chain.best_block().hash, third.hash());
+        assert!(chain.orphans().is_empty());
+        assert!(!chain.is_known(&invalid.hash()));
+    }
+
+    #[test]
+    fn rejects_orphans_beyond_sender_limit() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+        chain.set_orphan_limits(1, usize::MAX);
+
+        let first = child(&genesis, 100);
+        let second = child(&first, 100);
+        let third = child(&second, 100);
+
+        assert!(chain.import(second.clone(), 1).is_ok());
+        assert!(chain.import(second.clone(), 1).is_ok());
+        match chain.import(third.clone(), 1) {
+            Err(Error(ErrorKind::Block(BlockError::UnknownParent(_)), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(chain.import(third.clone(), 2).is_ok());
+        assert_eq!(chain.orphans().len(), 2);
+    }
+
+    #[test]
+    fn chain_info_from_genesis() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+        let header = child(&genesis, 100);
+        chain.insert(header.clone()).unwrap();
+
+        let info = chain.chain_info();
+        assert_eq!(info.genesis_hash, genesis.hash());
+        assert_eq!(info.best_block_hash, header.hash());
+        assert_eq!(info.best_block_number, 1);
+        assert_eq!(info.best_block_timestamp, header.timestamp());
+        assert_eq!(info.total_difficulty, U256::from(1100));
+        assert_eq!(info.pending_total_difficulty, U256::from(1100));
+        assert_eq!(info.ancient_block_hash, None);
+        assert_eq!(info.first_block_number, None);
+        assert_eq!(info.security_level(), SecurityLevel::FullProofOfWork);
+    }
+
+    #[test]
+    fn chain_info_from_checkpoint() {
+        let genesis = genesis();
+        let (checkpoint_header, checkpoint) = checkpoint();
+        let mut chain = HeaderChain::with_checkpoint(
+            Arc::new(NullEngine),
+            genesis.encoded(),
+            checkpoint,
+            Box::new(MemoryStore::new()),
+        )
+        .unwrap();
+
+        let info = chain.chain_info();
+        assert_eq!(info.ancient_block_hash, Some(genesis.hash()));
+        assert_eq!(info.ancient_block_number, Some(0));
+        assert_eq!(info.first_block_hash, Some(checkpoint_header.hash()));
+        assert_eq!(info.first_block_number, Some(5000));
+        assert_eq!(info.security_level(), SecurityLevel::PartialProofOfWork(0));
+
+        let mut parent = checkpoint_header;
+        for _ in 0..3 {
+            let header = child(&parent, 100);
+            chain.insert(header.clone()).unwrap();
+            parent = header;
+        }
+        assert_eq!(
+            chain.chain_info().security_level(),
+            SecurityLevel::PartialProofOfWork(3)
+        );
+    }
+
+    #[test]
+    fn refuses_unroutable_reorg() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+        chain.set_max_reorg_depth(Some(10));
+
+        let a1 = child(&genesis, 100);
+        let a2 = child(&a1, 100);
+        let b1 = child(&genesis, 50);
+        let b2 = child(&b1, 50);
+        for header in [&a1, &a2, &b1, &b2] {
+            chain.insert(header.clone()).unwrap();
+        }
+
+        // lose an ancestor of the fork, as an inconsistent store would.
+        let mut batch = Batch::default();
+        batch.remove_header(b1.hash());
+        chain.store.write(batch).unwrap();
+
+        let b3 = child(&b2, 500);
+        match chain.insert(b3.clone()) {
+            Err(Error(ErrorKind::Io(_), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(chain.best_block().hash, a2.hash());
+        assert!(!chain.is_known(&b3.hash()));
+    }
+
+    #[test]
+    fn notifies_subscribers() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+        let (notify, events) = ChannelNotify::new();
+        chain.add_notify(Arc::new(notify));
+        chain.set_finality_depth(Some(2));
+
+        let a1 = child(&genesis, 100);
+        let a2 = child(&a1, 100);
+        let a3 = child(&a2, 100);
+        let b3 = child(&a2, 50);
+        let b4 = child(&b3, 100);
+        // a fork which takes over two blocks higher, finalizing two blocks
+        // at once.
+        let c3 = child(&a2, 10);
+        let c4 = child(&c3, 10);
+        let c5 = child(&c4, 10);
+        let c6 = child(&c5, 200);
+        for header in [&a1, &a2, &a3, &b3, &b4, &c3, &c4, &c5, &c6] {
+            chain.insert(header.clone()).unwrap();
+        }
+
+        let events: Vec<_> = events.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                ChainEvent::Finalized(genesis.hash()),
+                ChainEvent::Enacted(vec![a1.hash()]),
+                ChainEvent::Enacted(vec![a2.hash()]),
+                ChainEvent::Enacted(vec![a3.hash()]),
+                ChainEvent::Finalized(a1.hash()),
+                ChainEvent::Retracted(vec![a3.hash()]),
+                ChainEvent::Enacted(vec![b3.hash(), b4.hash()]),
+                ChainEvent::Finalized(a2.hash()),
+                ChainEvent::Retracted(vec![b4.hash(), b3.hash()]),
+                ChainEvent::Enacted(vec![c3.hash(), c4.hash(), c5.hash(), c6.hash()]),
+                ChainEvent::Finalized(c3.hash()),
+                ChainEvent::Finalized(c4.hash()),
+            ]
+        );
+    }
+
+    #[test]
+    fn imports_from_verification_queue() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let mut headers = Vec::new();
+        let mut parent = genesis.clone();
+        for _ in 0..10 {
+            parent = child(&parent, 100);
+            headers.push(parent.clone());
+        }
+
+        let bad = headers[6].hash();
+        let verifier = move |header: &Header| -> Result<(), Error> {
+            if header.hash() == bad {
+                Err(ErrorKind::PowInvalid.into())
+            } else {
+                Ok(())
+            }
+        };
+        let queue =
+            VerificationQueue::new(verification_queue::Config::default(), Arc::new(verifier))
+                .unwrap();
+        for header in &headers {
+            queue.import(header.clone()).unwrap();
+        }
+        queue.flush();
+
+        let result = chain.import_verified(&queue);
+        let imported: Vec<_> = headers[..6].iter().map(|header| header.hash()).collect();
+        assert_eq!(result.imported, imported);
+        assert_eq!(result.bad.len(), 4);
+        match result.bad[0] {
+            (hash, Error(ErrorKind::PowInvalid, _)) => assert_eq!(hash, bad),
+            ref other => panic!("unexpected failure: {:?}", other),
+        }
+        assert_eq!(chain.best_block().hash, headers[5].hash());
+    }
+
+    #[test]
+    fn runs_engine_family_checks() {
+        struct IncreasingTimestamps;
+        impl Engine for IncreasingTimestamps {
+            fn verify_block_family(&self, header: &Header, parent: &Header) -> Result<(), Error> {
+                if header.timestamp() <= parent.timestamp() {
+                    return Err(BlockError::InvalidSeal.into());
+                }
+                Ok(())
+            }
+        }
+
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(IncreasingTimestamps), genesis.encoded());
+        let good = child(&genesis, 100);
+        chain.insert(good.clone()).unwrap();
+
+        let mut bad = child(&good, 100);
+        bad.set_timestamp(good.timestamp());
+        match chain.insert(bad.clone()) {
+            Err(Error(ErrorKind::Block(BlockError::InvalidSeal), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(!chain.is_known(&bad.hash()));
+        assert_eq!(chain.best_block().hash, good.hash());
+    }
+
+    #[test]
+    fn import_runs_engine_seal_checks() {
+        /// Rejects headers without extra data in the basic stage and those
+        /// with a difficulty of 13 in the unordered stage.
+        struct SealChecks;
+        impl Engine for SealChecks {
+            fn verify_block_basic(&self, header: &Header) -> Result<(), Error> {
+                if header.extra_data().is_empty() {
+                    return Err(BlockError::InvalidSeal.into());
+                }
+                Ok(())
+            }
+
+            fn verify_block_unordered(&self, header: &Header) -> Result<(), Error> {
+                if *header.difficulty() == U256::from(13) {
+                    return Err(ErrorKind::PowInvalid.into());
+                }
+                Ok(())
+            }
+        }
+        let sealed = |parent: &Header, difficulty: u64| {
+            let mut header = child(parent, difficulty);
+            header.set_extra_data(vec![1]);
+            header
+        };
+
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(SealChecks), genesis.encoded());
+        let good = sealed(&genesis, 100);
+
+        let unsealed = child(&genesis, 100);
+        match chain.import(unsealed.clone(), 1) {
+            Err(Error(ErrorKind::Block(BlockError::InvalidSeal), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let bad_pow = sealed(&genesis, 13);
+        match chain.import(bad_pow.clone(), 1) {
+            Err(Error(ErrorKind::PowInvalid, _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // orphans are checked before they are pooled.
+        match chain.import(child(&good, 100), 1) {
+            Err(Error(ErrorKind::Block(BlockError::InvalidSeal), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(chain.orphans().is_empty());
+        assert!(!chain.is_known(&unsealed.hash()));
+        assert!(!chain.is_known(&bad_pow.hash()));
+
+        chain.import(good.clone(), 1).unwrap();
+        assert_eq!(chain.best_block().hash, good.hash());
+    }
+
+    #[test]
+    fn uses_configured_fork_choice() {
+        /// Prefers the header with the lowest difficulty.
+        struct Lightest;
+
+        impl ForkChoiceRule<ExtendedHeader> for Lightest {
+            fn fork_choice(&self, new: &ExtendedHeader, best: &ExtendedHeader) -> ForkChoice {
+                if new.header.difficulty() < best.header.difficulty() {
+                    ForkChoice::New
+                } else {
+                    ForkChoice::Old
+                }
+            }
+        }
+
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+        chain.set_fork_choice(Box::new(Lightest));
+
+        let heavy = child(&genesis, 500);
+        let light = child(&genesis, 100);
+        chain.insert(heavy.clone()).unwrap();
+        assert_eq!(chain.best_block().hash, heavy.hash());
+        chain.insert(light.clone()).unwrap();
+        assert_eq!(chain.best_block().hash, light.hash());
+        assert_eq!(chain.best_block().total_difficulty, U256::from(1100));
+    }
+
+    #[test]
+    fn proof_of_authority_follows_engine_finality() {
+        /// Considers headers with extra data final.
+        struct SignedFinality;
+        impl Engine for SignedFinality {
+            fn is_finalized(&self, header: &Header) -> bool {
+                !header.extra_data().is_empty()
+            }
+        }
+
+        let genesis = genesis();
+        let heavy = child(&genesis, 500);
+        let mut finalized = child(&genesis, 100);
+        finalized.set_extra_data(vec![1]);
+        let heavier = child(&heavy, 10_000);
+
+        // total difficulty ignores finality.
+        let mut chain = HeaderChain::new(Arc::new(SignedFinality), genesis.encoded());
+        chain.insert(heavy.clone()).unwrap();
+        chain.insert(finalized.clone()).unwrap();
+        assert_eq!(chain.best_block().hash, heavy.hash());
+        assert_eq!(chain.finalized_number(), None);
+
+        let mut chain = HeaderChain::new(Arc::new(SignedFinality), genesis.encoded());
+        chain.set_fork_choice(Box::new(ProofOfAuthority));
+        chain.insert(heavy.clone()).unwrap();
+        chain.insert(finalized.clone()).unwrap();
+        assert_eq!(chain.best_block().hash, finalized.hash());
+        assert_eq!(chain.finalized_number(), Some(1));
+        assert!(chain.is_finalized(&finalized.hash()));
+
+        // a heavier fork can't retract the finalized header.
+        chain.insert(heavier.clone()).unwrap();
+        assert_eq!(chain.best_block().hash, finalized.hash());
+        assert!(chain.is_known(&heavier.hash()));
+    }
+
+    #[test]
+    fn quarantines_deep_reorgs() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+        chain.set_max_reorg_depth(Some(3));
+
+        let mut headers = vec![genesis.clone()];
+        for _ in 0..10 {
+            let header = child(headers.last().unwrap(), 100);
+            chain.insert(header.clone()).unwrap();
+            headers.push(header);
+        }
+        let best = chain.best_block();
+
+        // branching off block 5 would retract blocks 6 to 10.
+        let deep = child(&headers[5], 10_000);
+        match chain.insert(deep.clone()) {
+            Err(Error(ErrorKind::ReorgTooDeep(5, 3), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let deep_child = child(&deep, 100);
+        match chain.import(deep_child.clone(), 1) {
+            Err(Error(ErrorKind::ReorgTooDeep(5, 3), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(chain.best_block(), best);
+        assert!(!chain.is_known(&deep.hash()));
+        assert!(chain.is_quarantined(&deep.hash()));
+        assert!(chain.is_quarantined(&deep_child.hash()));
+
+        // branching off block 8 only retracts two blocks.
+        let shallow = child(&headers[8], 10_000);
+        chain.insert(shallow.clone()).unwrap();
+        assert_eq!(chain.best_block().hash, shallow.hash());
+
+        assert_eq!(chain.take_quarantined(), vec![deep, deep_child]);
+        assert!(chain.take_quarantined().is_empty());
+    }
+
+    #[test]
+    fn reverts_to_canonical_ancestor() {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+        let (notify, events) = ChannelNotify::new();
+        chain.add_notify(Arc::new(notify));
+
+        let mut headers = vec![genesis.clone()];
+        for _ in 0..10 {
+            let header = child(headers.last().unwrap(), 100);
+            chain.insert(header.clone()).unwrap();
+            headers.push(header);
+        }
+        let side = child(&headers[7], 50);
+        let low_side = child(&headers[5], 50);
+        chain.insert(side.clone()).unwrap();
+        chain.insert(low_side.clone()).unwrap();
+        events.try_iter().count();
+
+        let retracted = chain.revert_to(BlockId::Number(6)).unwrap();
+        let expected: Vec<_> = headers[7..].iter().rev().map(|h| h.hash()).collect();
+        assert_eq!(retracted, expected);
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![ChainEvent::Retracted(expected)]
+        );
+
+        let best = chain.best_block();
+        assert_eq!(best.hash, headers[6].hash());
+        assert_eq!(best.total_difficulty, U256::from(1600));
+        assert_eq!(chain.block_hash(BlockId::Number(7)), None);
+        assert!(!chain.is_known(&headers[8].hash()));
+        assert!(!chain.is_known(&side.hash()));
+        assert!(chain.is_known(&low_side.hash()));
+
+        // retracted blocks can be imported again.
+        chain.insert(headers[7].clone()).unwrap();
+        assert_eq!(chain.best_block().hash, headers[7].hash());
+
+        assert!(chain.revert_to(BlockId::Hash(side.hash())).is_err());
+        match chain.revert_to(BlockId::Number(50)) {
+            Err(Error(ErrorKind::UnavailableBlock(50), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match chain.revert_to(BlockId::Hash(low_side.hash())) {
+            Err(Error(ErrorKind::NotCanonical(_), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn refuses_to_revert_past_boundaries() {
+        let genesis = genesis();
+        let (checkpoint_header, checkpoint) = checkpoint();
+        let mut chain = HeaderChain::with_checkpoint(
+            Arc::new(NullEngine),
+            genesis.encoded(),
+            checkpoint,
+            Box::new(MemoryStore::new()),
+        )
+        .unwrap();
+
+        let mut parent = checkpoint_header.clone();
+        for _ in 0..10 {
+            parent = child(&parent, 100);
+            chain.insert(parent.clone()).unwrap();
+        }
+
+        match chain.revert_to(BlockId::Earliest) {
+            Err(Error(ErrorKind::RevertBelowBoundary(0, 5000), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        chain.set_finality_depth(Some(4));
+        match chain.revert_to(BlockId::Number(5005)) {
+            Err(Error(ErrorKind::RevertBelowBoundary(5005, 5006), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        chain.revert_to(BlockId::Number(5006)).unwrap();
+        assert_eq!(chain.best_block().number, 5006);
+        assert_eq!(chain.revert_to(BlockId::Latest).unwrap(), Vec::new());
+    }
 }
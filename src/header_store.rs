@@ -0,0 +1,735 @@
+// Copyright 2019 Chainpool
+
+//! Storage backends for the header chain.
+//!
+//! A store keeps headers by hash, the candidate entry (including the canonical
+//! hash and total difficulties) for every height, the best and first block
+//! pointers and the known CHT roots. All writes go through a `Batch` which is
+//! applied atomically.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::encoded;
+use crate::header::BlockNumber;
+use crate::header_chain::Entry;
+use ethereum_types::{H256, U256};
+use hash::keccak;
+use rlp::{self, Decodable, DecoderError, Encodable, Rlp, RlpStream};
+
+/// Backing storage for the header chain.
+pub trait HeaderStore: Send {
+    /// Get a header by hash.
+    fn header(&self, hash: &H256) -> Option<encoded::Header>;
+
+    /// Get the entry holding all known headers at the given height.
+    fn entry(&self, number: BlockNumber) -> Option<Entry>;
+
+    /// Get the hash of the best block, if one was ever written.
+    fn best_block(&self) -> Option<H256>;
+
+    /// Get the hash of the block the chain was started from.
+    fn first_block(&self) -> Option<H256>;
+
+    /// Get the root of the CHT with the given index.
+    fn cht_root(&self, index: u64) -> Option<H256>;
+
+    /// Atomically apply a batch of writes.
+    fn write(&mut self, batch: Batch) -> io::Result<()>;
+
+    /// Reclaim the space taken by data which later writes removed or
+    /// replaced. Stores which keep nothing on disk have nothing to do.
+    fn compact(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Get the canonical hash at the given height, as of the last time the
+    /// entry was written.
+    fn canonical_hash(&self, number: BlockNumber) -> Option<H256> {
+        self.entry(number).map(|entry| entry.canonical_hash)
+    }
+
+    /// Get the total difficulty of a stored header.
+    fn total_difficulty(&self, hash: &H256) -> Option<U256> {
+        let number = self.header(hash)?.number();
+        self.entry(number)?
+            .candidates
+            .into_iter()
+            .find(|c| &c.hash == hash)
+            .map(|c| c.total_difficulty)
+    }
+}
+
+/// A set of writes to be applied to a store at once.
+///
+/// Removals are applied before insertions.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Batch {
+    headers: Vec<(H256, encoded::Header)>,
+    entries: Vec<(BlockNumber, Entry)>,
+    best_block: Option<H256>,
+    first_block: Option<H256>,
+    cht_roots: Vec<(u64, H256)>,
+    removed_headers: Vec<H256>,
+    removed_entries: Vec<BlockNumber>,
+}
+
+impl Batch {
+    /// Insert a header.
+    pub fn insert_header(&mut self, hash: H256, header: encoded::Header) {
+        self.headers.push((hash, header));
+    }
+
+    /// Insert or replace the entry at the given height.
+    pub fn insert_entry(&mut self, number: BlockNumber, entry: Entry) {
+        self.entries.push((number, entry));
+    }
+
+    /// Set the best block pointer.
+    pub fn set_best_block(&mut self, hash: H256) {
+        self.best_block = Some(hash);
+    }
+
+    /// Set the first block pointer.
+    pub fn set_first_block(&mut self, hash: H256) {
+        self.first_block = Some(hash);
+    }
+
+    /// Insert the root of the CHT with the given index.
+    pub fn insert_cht_root(&mut self, index: u64, root: H256) {
+        self.cht_roots.push((index, root));
+    }
+
+    /// Remove a header.
+    pub fn remove_header(&mut self, hash: H256) {
+        self.removed_headers.push(hash);
+    }
+
+    /// Remove the entry at the given height.
+    pub fn remove_entry(&mut self, number: BlockNumber) {
+        self.removed_entries.push(number);
+    }
+
+    /// Whether the batch contains no writes.
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+            && self.entries.is_empty()
+            && self.best_block.is_none()
+            && self.first_block.is_none()
+            && self.cht_roots.is_empty()
+            && self.removed_headers.is_empty()
+            && self.removed_entries.is_empty()
+    }
+}
+
+impl Encodable for Batch {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(7);
+
+        s.begin_list(self.headers.len());
+        for (hash, header) in &self.headers {
+            s.begin_list(2)
+                .append(hash)
+                .append_raw(header.rlp().as_raw(), 1);
+        }
+
+        s.begin_list(self.entries.len());
+        for (number, entry) in &self.entries {
+            s.begin_list(2).append(number).append(entry);
+        }
+
+        append_optional_hash(s, &self.best_block);
+        append_optional_hash(s, &self.first_block);
+
+        s.begin_list(self.cht_roots.len());
+        for (index, root) in &self.cht_roots {
+            s.begin_list(2).append(index).append(root);
+        }
+
+        s.append_list(&self.removed_headers);
+        s.append_list(&self.removed_entries);
+    }
+}
+
+impl Decodable for Batch {
+    fn decode(rlp: &Rlp) -> Result<Self, DecoderError> {
+        let headers: Vec<(H256, encoded::Header)> = rlp
+            .at(0)?
+            .iter()
+            .map(|item| {
+                Ok((
+                    item.val_at(0)?,
+                    encoded::Header::new(item.at(1)?.as_raw().to_vec()),
+                ))
+            })
+            .collect::<Result<_, DecoderError>>()?;
+
+        let entries: Vec<(BlockNumber, Entry)> = rlp
+            .at(1)?
+            .iter()
+            .map(|item| Ok((item.val_at(0)?, item.val_at(1)?)))
+            .collect::<Result<_, DecoderError>>()?;
+
+        let cht_roots: Vec<(u64, H256)> = rlp
+            .at(4)?
+            .iter()
+            .map(|item| Ok((item.val_at(0)?, item.val_at(1)?)))
+            .collect::<Result<_, DecoderError>>()?;
+
+        Ok(Batch {
+            headers,
+            entries,
+            best_block: decode_optional_hash(&rlp.at(2)?)?,
+            first_block: decode_optional_hash(&rlp.at(3)?)?,
+            cht_roots,
+            removed_headers: rlp.list_at(5)?,
+            removed_entries: rlp.list_at(6)?,
+        })
+    }
+}
+
+fn append_optional_hash(s: &mut RlpStream, hash: &Option<H256>) {
+    match *hash {
+        Some(ref hash) => s.append(hash),
+        None => s.append_empty_data(),
+    };
+}
+
+fn decode_optional_hash(rlp: &Rlp) -> Result<Option<H256>, DecoderError> {
+    if rlp.is_empty() {
+        Ok(None)
+    } else {
+        rlp.as_val().map(Some)
+    }
+}
+
+/// Header store which keeps everything in memory.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    headers: HashMap<H256, encoded::Header>,
+    entries: BTreeMap<BlockNumber, Entry>,
+    best_block: Option<H256>,
+    first_block: Option<H256>,
+    cht_roots: BTreeMap<u64, H256>,
+}
+
+impl MemoryStore {
+    /// Create a new, empty, in-memory store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn apply(&mut self, batch: Batch) {
+        for hash in &batch.removed_headers {
+            self.headers.remove(hash);
+        }
+        for number in &batch.removed_entries {
+            self.entries.remove(number);
+        }
+
+        self.headers.extend(batch.headers);
+        self.entries.extend(batch.entries);
+        self.cht_roots.extend(batch.cht_roots);
+        if let Some(best_block) = batch.best_block {
+            self.best_block = Some(best_block);
+        }
+        if let Some(first_block) = batch.first_block {
+            self.first_block = Some(first_block);
+        }
+    }
+
+    /// A batch which recreates the full contents of this store.
+    fn snapshot(&self) -> Batch {
+        Batch {
+            headers: self
+                .headers
+                .iter()
+                .map(|(hash, header)| (*hash, header.clone()))
+                .collect(),
+            entries: self
+                .entries
+                .iter()
+                .map(|(number, entry)| (*number, entry.clone()))
+                .collect(),
+            best_block: self.best_block,
+            first_block: self.first_block,
+            cht_roots: self
+                .cht_roots
+                .iter()
+                .map(|(index, root)| (*index, *root))
+                .collect(),
+            removed_headers: Vec::new(),
+            removed_entries: Vec::new(),
+        }
+    }
+}
+
+impl HeaderStore for MemoryStore {
+    fn header(&self, hash: &H256) -> Option<encoded::Header> {
+        self.headers.get(hash).cloned()
+    }
+
+    fn entry(&self, number: BlockNumber) -> Option<Entry> {
+        self.entries.get(&number).cloned()
+    }
+
+    fn best_block(&self) -> Option<H256> {
+        self.best_block
+    }
+
+    fn first_block(&self) -> Option<H256> {
+        self.first_block
+    }
+
+    fn cht_root(&self, index: u64) -> Option<H256> {
+        self.cht_roots.get(&index).cloned()
+    }
+
+    fn write(&mut self, batch: Batch) -> io::Result<()> {
+        self.apply(batch);
+        Ok(())
+    }
+}
+
+/// Length of the record length prefix and payload checksum.
+const RECORD_HEADER_LEN: usize = 4 + 32;
+
+/// Header store which journals every batch to a file.
+///
+/// Each batch is appended as a single record, prefixed with its length and
+/// keccak checksum, and synced to disk before it is applied in memory. On
+/// open the journal is replayed and a trailing record torn by a crash is
+/// discarded, so the store always reflects a whole number of batches and the
+/// best block pointer always refers to fully written entries. A complete
+/// record which can't be decoded is corruption rather than a torn write, and
+/// fails the open instead of discarding it along with every later batch.
+///
+/// A write which fails partway is cut off the journal again, so later batches
+/// are never appended after a torn record. If even that fails the store is
+/// poisoned and refuses further writes until it is compacted.
+pub struct FileStore {
+    path: PathBuf,
+    file: File,
+    memory: MemoryStore,
+    /// Length of the journal up to the end of the last complete record.
+    len: u64,
+    poisoned: bool,
+}
+
+impl FileStore {
+    /// Open the journal at `path`, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let mut memory = MemoryStore::new();
+        let mut offset = 0;
+        while let Some((batch, len)) = decode_record(&bytes[offset..]).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupt header journal record at offset {}: {}", offset, e),
+            )
+        })? {
+            memory.apply(batch);
+            offset += len;
+        }
+
+        if offset < bytes.len() {
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+
+        Ok(FileStore {
+            path,
+            file,
+            memory,
+            len: offset as u64,
+            poisoned: false,
+        })
+    }
+
+    /// Cut whatever part of a failed write reached the journal.
+    fn rollback(&mut self) -> io::Result<()> {
+        self.file.set_len(self.len)?;
+        self.file.sync_all()
+    }
+}
+
+/// Make a rename within the directory of `path` durable.
+#[cfg(unix)]
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened for syncing on this platform.
+#[cfg(not(unix))]
+fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+impl HeaderStore for FileStore {
+    fn header(&self, hash: &H256) -> Option<encoded::Header> {
+        self.memory.header(hash)
+    }
+
+    fn entry(&self, number: BlockNumber) -> Option<Entry> {
+        self.memory.entry(number)
+    }
+
+    fn best_block(&self) -> Option<H256> {
+        self.memory.best_block()
+    }
+
+    fn first_block(&self) -> Option<H256> {
+        self.memory.first_block()
+    }
+
+    fn cht_root(&self, index: u64) -> Option<H256> {
+        self.memory.cht_root(index)
+    }
+
+    fn write(&mut self, batch: Batch) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if self.poisoned {
+            return Err(io::Error::other(
+                "header journal is poisoned by an earlier failed write",
+            ));
+        }
+
+        let record = encode_record(&batch)?;
+        let result = self
+            .file
+            .write_all(&record)
+            .and_then(|_| self.file.sync_data());
+        if let Err(e) = result {
+            if self.rollback().is_err() {
+                self.poisoned = true;
+            }
+            return Err(e);
+        }
+
+        self.len += record.len() as u64;
+        self.memory.apply(batch);
+        Ok(())
+    }
+
+    /// Rewrite the journal as a single record holding the current contents.
+    ///
+    /// The new journal is written next to the old one and moved into place,
+    /// so a crash during compaction leaves the old journal intact. This also
+    /// clears a poisoned store, as the journal is rebuilt from memory.
+    fn compact(&mut self) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        let record = encode_record(&self.memory.snapshot())?;
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&record)?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        sync_dir(&self.path)?;
+
+        self.file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        self.len = record.len() as u64;
+        self.poisoned = false;
+        Ok(())
+    }
+}
+
+fn encode_record(batch: &Batch) -> io::Result<Vec<u8>> {
+    frame_record(&rlp::encode(batch))
+}
+
+/// Prefix `payload` with its length and checksum.
+fn frame_record(payload: &[u8]) -> io::Result<Vec<u8>> {
+    let len = record_len(payload.len())?;
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+    record.extend_from_slice(&len.to_be_bytes());
+    record.extend_from_slice(&keccak(payload).0);
+    record.extend_from_slice(payload);
+    Ok(record)
+}
+
+/// Get the length prefix for a payload, which must fit 32 bits.
+fn record_len(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("header journal record of {} bytes is too large", len),
+        )
+    })
+}
+
+/// Decode the record at the start of `bytes`, returning the batch and the
+/// length of the record. Returns `None` for a record which is truncated or
+/// fails its checksum, as left by a torn write, and an error for a complete
+/// record which can't be decoded.
+fn decode_record(bytes: &[u8]) -> Result<Option<(Batch, usize)>, DecoderError> {
+    if bytes.len() < RECORD_HEADER_LEN {
+        return Ok(None);
+    }
+
+    let len = bytes[..4]
+        .iter()
+        .fold(0usize, |len, byte| (len << 8) | *byte as usize);
+    let end = match RECORD_HEADER_LEN.checked_add(len) {
+        Some(end) if end <= bytes.len() => end,
+        _ => return Ok(None),
+    };
+
+    let payload = &bytes[RECORD_HEADER_LEN..end];
+    if keccak(payload).0[..] != bytes[4..RECORD_HEADER_LEN] {
+        return Ok(None);
+    }
+
+    rlp::decode(payload).map(|batch| Some((batch, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Batch, FileStore, HeaderStore, MemoryStore};
+    use crate::header::Header;
+    use crate::header_chain::{Candidate, Entry};
+    use ethereum_types::U256;
+    use std::fs::{self, OpenOptions};
+    use std::io::{self, Write};
+    use tempdir::TempDir;
+
+    fn make_batch(number: u64) -> (Header, Batch) {
+        let mut header = Header::new();
+        header.set_number(number);
+        header.set_difficulty(U256::from(10));
+
+        let hash = header.hash();
+        let mut batch = Batch::default();
+        batch.insert_header(hash, header.encoded());
+        batch.insert_entry(
+            number,
+            Entry {
+                candidates: vec![Candidate {
+                    hash,
+                    parent_hash: *header.parent_hash(),
+                    total_difficulty: U256::from(10 * (number + 1)),
+                }],
+                canonical_hash: hash,
+            },
+        );
+        batch.set_best_block(hash);
+        batch.insert_cht_root(number, hash);
+        (header, batch)
+    }
+
+    #[test]
+    fn memory_store_applies_batches() {
+        let mut store = MemoryStore::new();
+        let (header, batch) = make_batch(3);
+        store.write(batch).unwrap();
+
+        assert_eq!(store.header(&header.hash()), Some(header.encoded()));
+        assert_eq!(store.canonical_hash(3), Some(header.hash()));
+        assert_eq!(store.total_difficulty(&header.hash()), Some(U256::from(40)));
+        assert_eq!(store.best_block(), Some(header.hash()));
+        assert_eq!(store.first_block(), None);
+        assert_eq!(store.cht_root(3), Some(header.hash()));
+        assert_eq!(store.canonical_hash(4), None);
+
+        let mut batch = Batch::default();
+        batch.remove_header(header.hash());
+        batch.remove_entry(3);
+        store.write(batch).unwrap();
+
+        assert_eq!(store.header(&header.hash()), None);
+        assert_eq!(store.entry(3), None);
+        assert_eq!(store.cht_root(3), Some(header.hash()));
+    }
+
+    #[test]
+    fn batch_rlp_roundtrip() {
+        let (header, mut batch) = make_batch(7);
+        batch.set_first_block(header.hash());
+        batch.remove_header(header.hash());
+        batch.remove_entry(6);
+        let encoded = rlp::encode(&batch).to_vec();
+        assert_eq!(rlp::decode::<Batch>(&encoded).unwrap(), batch);
+    }
+
+    #[test]
+    fn file_store_survives_reopen() {
+        let tempdir = TempDir::new("header-store").unwrap();
+        let path = tempdir.path().join("headers");
+
+        let (first, first_batch) = make_batch(1);
+        let (second, second_batch) = make_batch(2);
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.write(first_batch).unwrap();
+            store.write(second_batch).unwrap();
+        }
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.header(&first.hash()), Some(first.encoded()));
+        assert_eq!(store.canonical_hash(2), Some(second.hash()));
+        assert_eq!(store.best_block(), Some(second.hash()));
+    }
+
+    #[test]
+    fn file_store_discards_torn_record() {
+        let tempdir = TempDir::new("header-store").unwrap();
+        let path = tempdir.path().join("headers");
+
+        let (first, first_batch) = make_batch(1);
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.write(first_batch).unwrap();
+        }
+
+        // simulate a crash halfway through writing the second record.
+        let (_, second_batch) = make_batch(2);
+        let record = super::encode_record(&second_batch).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&record[..record.len() / 2])
+            .unwrap();
+
+        let mut store = FileStore::open(&path).unwrap();
+        assert_eq!(store.best_block(), Some(first.hash()));
+        assert_eq!(store.entry(2), None);
+
+        // the torn tail is truncated, so later writes are readable again.
+        let (third, third_batch) = make_batch(3);
+        store.write(third_batch).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.best_block(), Some(third.hash()));
+    }
+
+    #[test]
+    fn file_store_refuses_corrupt_record() {
+        let tempdir = TempDir::new("header-store").unwrap();
+        let path = tempdir.path().join("headers");
+
+        let (_, first_batch) = make_batch(1);
+        let (_, third_batch) = make_batch(3);
+        {
+            let mut store = FileStore::open(&path).unwrap();
+            store.write(first_batch).unwrap();
+        }
+
+        // a complete record with a valid checksum which isn't a batch,
+        // followed by a good one.
+        let mut journal = OpenOptions::new().append(true).open(&path).unwrap();
+        journal
+            .write_all(&super::frame_record(b"garbage").unwrap())
+            .unwrap();
+        journal
+            .write_all(&super::encode_record(&third_batch).unwrap())
+            .unwrap();
+        let len = journal.metadata().unwrap().len();
+        drop(journal);
+
+        let err = FileStore::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn oversized_records_are_refused() {
+        assert_eq!(super::record_len(u32::MAX as usize).unwrap(), u32::MAX);
+        let err = super::record_len(u32::MAX as usize + 1).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn file_store_compaction_keeps_contents() {
+        let tempdir = TempDir::new("header-store").unwrap();
+        let path = tempdir.path().join("headers");
+
+        let mut store = FileStore::open(&path).unwrap();
+        let mut last = None;
+        for number in 0..5 {
+            let (header, batch) = make_batch(number);
+            store.write(batch).unwrap();
+            last = Some(header);
+        }
+        store.compact().unwrap();
+
+        let (extra, extra_batch) = make_batch(5);
+        store.write(extra_batch).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.canonical_hash(4), last.map(|header| header.hash()));
+        assert_eq!(store.best_block(), Some(extra.hash()));
+    }
+
+    #[test]
+    fn file_store_rolls_back_failed_write() {
+        let tempdir = TempDir::new("header-store").unwrap();
+        let path = tempdir.path().join("headers");
+
+        let (first, first_batch) = make_batch(1);
+        let mut store = FileStore::open(&path).unwrap();
+        store.write(first_batch).unwrap();
+
+        // part of a record which failed to be written in full.
+        let (_, second_batch) = make_batch(2);
+        let record = super::encode_record(&second_batch).unwrap();
+        store.file.write_all(&record[..record.len() / 2]).unwrap();
+        store.rollback().unwrap();
+
+        let (third, third_batch) = make_batch(3);
+        store.write(third_batch).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.header(&first.hash()), Some(first.encoded()));
+        assert_eq!(store.best_block(), Some(third.hash()));
+        assert_eq!(store.entry(2), None);
+    }
+
+    #[test]
+    fn file_store_poisoned_until_compacted() {
+        let tempdir = TempDir::new("header-store").unwrap();
+        let path = tempdir.path().join("headers");
+
+        let (first, first_batch) = make_batch(1);
+        let mut store = FileStore::open(&path).unwrap();
+        store.write(first_batch).unwrap();
+
+        // neither writing nor truncating works through a read-only handle.
+        store.file = OpenOptions::new().read(true).open(&path).unwrap();
+        let (second, second_batch) = make_batch(2);
+        assert!(store.write(second_batch.clone()).is_err());
+        assert!(store.poisoned);
+
+        store.compact().unwrap();
+        store.write(second_batch).unwrap();
+        drop(store);
+
+        let store = FileStore::open(&path).unwrap();
+        assert_eq!(store.header(&first.hash()), Some(first.encoded()));
+        assert_eq!(store.best_block(), Some(second.hash()));
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright 2019 Chainpool
+
+//! Export and import of canonical headers as RLP streams.
+//!
+//! A stream is nothing but RLP-encoded headers written back to back, the
+//! same format parity uses for `export blocks`. Imported headers go through
+//! a `VerificationQueue` before being inserted, exactly like headers
+//! received from the network.
+
+use std::io::{self, BufReader, Read, Write};
+
+use crate::engine::Engine;
+use crate::error::{Error, ErrorKind};
+use crate::header::{BlockNumber, Header};
+use crate::header_chain::{HeaderChain, ImportedHeaders};
+use crate::verification_queue::VerificationQueue;
+use rlp;
+use types::ids::BlockId;
+
+/// Maximum size of a single RLP item accepted from a stream, in bytes.
+pub const MAX_ITEM_SIZE: usize = 1024 * 1024;
+
+/// Write the canonical headers from `from` to `to` inclusive to `out`.
+///
+/// Returns the number of headers written. Fails with
+/// `ErrorKind::UnavailableBlock` if a header in the range is not held in
+/// full, e.g. because it was pruned or is after the best block.
+pub fn export_headers<E: Engine, W: Write>(
+    chain: &HeaderChain<E>,
+    from: BlockNumber,
+    to: BlockNumber,
+    mut out: W,
+) -> Result<u64, Error> {
+    let mut written = 0;
+    for number in from..=to {
+        let header = chain
+            .header(BlockId::Number(number))
+            .ok_or(ErrorKind::UnavailableBlock(number))?;
+        out.write_all(&rlp::encode(&header.decode()?))?;
+        written += 1;
+    }
+    out.flush()?;
+    Ok(written)
+}
+
+/// Read headers from `input`, verify them in `queue` and insert them into
+/// `chain`.
+///
+/// Headers must be ordered parents first, although they need not connect to
+/// the best block. Headers which fail verification or insertion are
+/// reported as bad without aborting the import; a malformed stream or a
+/// read error does abort it, leaving the headers read so far queued.
+pub fn import_headers<E: Engine, R: Read>(
+    chain: &mut HeaderChain<E>,
+    queue: &VerificationQueue,
+    input: R,
+) -> Result<ImportedHeaders, Error> {
+    let mut result = ImportedHeaders::default();
+    for header in HeaderReader::new(input) {
+        let header = header?;
+        match queue.import(header.clone()) {
+            Err(Error(ErrorKind::QueueFull(_), _)) => {
+                // make room by inserting what has been verified so far.
+                queue.flush();
+                merge(&mut result, chain.import_verified(queue));
+                queue.import(header)?;
+            }
+            Ok(_) | Err(Error(ErrorKind::AlreadyQueued, _)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    queue.flush();
+    merge(&mut result, chain.import_verified(queue));
+    Ok(result)
+}
+
+fn merge(result: &mut ImportedHeaders, other: ImportedHeaders) {
+    result.imported.extend(other.imported);
+    result.bad.extend(other.bad);
+}
+
+/// Iterator over the headers in an RLP stream.
+pub struct HeaderReader<R> {
+    input: BufReader<R>,
+}
+
+impl<R: Read> HeaderReader<R> {
+    /// Create a reader over a stream of RLP-encoded headers.
+    pub fn new(input: R) -> Self {
+        HeaderReader {
+            input: BufReader::new(input),
+        }
+    }
+
+    fn read_item(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut prefix = [0u8; 1];
+        if self.input.read(&mut prefix)? == 0 {
+            return Ok(None);
+        }
+
+        let mut item = vec![prefix[0]];
+        let payload = match prefix[0] {
+            0x00..=0x7f => 0,
+            0x80..=0xb7 => (prefix[0] - 0x80) as usize,
+            0xc0..=0xf7 => (prefix[0] - 0xc0) as usize,
+            _ => {
+                let len_of_len = (prefix[0] - if prefix[0] < 0xc0 { 0xb7 } else { 0xf7 }) as usize;
+                let mut len = [0u8; 8];
+                self.input.read_exact(&mut len[8 - len_of_len..])?;
+                item.extend_from_slice(&len[8 - len_of_len..]);
+                u64::from_be_bytes(len) as usize
+            }
+        };
+        if payload > MAX_ITEM_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "RLP item exceeds the maximum size",
+            ));
+        }
+
+        let start = item.len();
+        item.resize(start + payload, 0);
+        self.input.read_exact(&mut item[start..])?;
+        Ok(Some(item))
+    }
+}
+
+impl<R: Read> Iterator for HeaderReader<R> {
+    type Item = Result<Header, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.read_item() {
+            Ok(Some(item)) => Some(rlp::decode(&item).map_err(Into::into)),
+            Ok(None) => None,
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{export_headers, import_headers, HeaderReader};
+    use crate::engine::NullEngine;
+    use crate::error::{Error, ErrorKind};
+    use crate::header::Header;
+    use crate::header_chain::HeaderChain;
+    use crate::test_helpers::{child, genesis};
+    use crate::verification_queue::{Config, VerificationQueue};
+    use ethereum_types::U256;
+    use std::sync::Arc;
+
+    fn queue(max_queue_size: usize) -> VerificationQueue {
+        let config = Config {
+            max_queue_size,
+            max_mem_use: usize::MAX,
+            num_verifiers: 2,
+        };
+        let verifier = |header: &Header| -> Result<(), Error> {
+            if header.difficulty() == &U256::zero() {
+                Err(ErrorKind::PowInvalid.into())
+            } else {
+                Ok(())
+            }
+        };
+        VerificationQueue::new(config, Arc::new(verifier)).unwrap()
+    }
+
+    fn exported_chain(length: usize) -> (Vec<Header>, Vec<u8>) {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+        let mut headers = vec![genesis];
+        for _ in 0..length {
+            let mut header = child(headers.last().unwrap(), 100);
+            header.set_extra_data(vec![7; 40]);
+            chain.insert(header.clone()).unwrap();
+            headers.push(header);
+        }
+
+        let mut out = Vec::new();
+        let written = export_headers(&chain, 0, length as u64, &mut out).unwrap();
+        assert_eq!(written, length as u64 + 1);
+        (headers, out)
+    }
+
+    #[test]
+    fn roundtrips_canonical_headers() {
+        let (headers, stream) = exported_chain(20);
+        let read: Vec<Header> = HeaderReader::new(&stream[..])
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, headers);
+
+        // a small queue forces the import to insert in several rounds.
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), headers[0].encoded());
+        let result = import_headers(&mut chain, &queue(3), &stream[..]).unwrap();
+        assert!(result.bad.is_empty());
+        assert_eq!(result.imported.len(), 21);
+        assert_eq!(chain.best_block().hash, headers[20].hash());
+    }
+
+    #[test]
+    fn reports_bad_headers() {
+        let (headers, _) = exported_chain(5);
+        let bad = child(&headers[2], 0);
+
+        let mut stream = Vec::new();
+        for header in headers[1..3].iter().chain(Some(&bad)).chain(&headers[3..]) {
+            stream.extend(rlp::encode(header));
+        }
+
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), headers[0].encoded());
+        let result = import_headers(&mut chain, &queue(100), &stream[..]).unwrap();
+        assert_eq!(result.imported.len(), 5);
+        assert_eq!(result.bad.len(), 1);
+        assert_eq!(result.bad[0].0, bad.hash());
+        assert_eq!(chain.best_block().hash, headers[5].hash());
+    }
+
+    #[test]
+    fn rejects_truncated_stream() {
+        let (headers, mut stream) = exported_chain(3);
+        stream.truncate(stream.len() - 1);
+
+        let mut reader = HeaderReader::new(&stream[..]);
+        for header in &headers[..3] {
+            assert_eq!(&reader.next().unwrap().unwrap(), header);
+        }
+        match reader.next() {
+            Some(Err(Error(ErrorKind::Io(_), _))) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), headers[0].encoded());
+        assert!(import_headers(&mut chain, &queue(100), &stream[..]).is_err());
+    }
+
+    #[test]
+    fn refuses_unavailable_range() {
+        let (headers, _) = exported_chain(0);
+        let chain = HeaderChain::new(Arc::new(NullEngine), headers[0].encoded());
+        match export_headers(&chain, 0, 1, Vec::new()) {
+            Err(Error(ErrorKind::UnavailableBlock(1), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,436 @@
+// Copyright 2019 Chainpool
+
+//! Consistency checks for a stored header chain.
+//!
+//! After an unclean shutdown the store should be checked before a
+//! `HeaderChain` is opened on it. `check` walks every stored height from the
+//! earliest block kept in full, verifying that headers link up, that numbers
+//! and total difficulties accumulate correctly and that the canonical index
+//! forms a single chain ending in the best block.
+
+use std::fmt;
+
+use crate::cht;
+use crate::header::BlockNumber;
+use crate::header_chain::{first_prunable_cht, Candidate};
+use crate::header_store::HeaderStore;
+use ethereum_types::{H256, U256};
+
+/// A single inconsistency found in the store.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Corruption {
+    /// The best block pointer is missing.
+    MissingBestBlock,
+    /// The first block pointer or its header is missing.
+    MissingFirstBlock,
+    /// No entry is stored at the given height.
+    MissingEntry(BlockNumber),
+    /// A candidate's header is not stored.
+    MissingHeader(BlockNumber, H256),
+    /// A header is stored under a hash which is not its own.
+    HashMismatch(BlockNumber, H256),
+    /// A header is stored at the wrong height.
+    NumberMismatch {
+        hash: H256,
+        expected: BlockNumber,
+        found: BlockNumber,
+    },
+    /// A candidate's parent hash disagrees with its header.
+    ParentMismatch(BlockNumber, H256),
+    /// A candidate's parent is not stored at the height below.
+    UnknownParent(BlockNumber, H256),
+    /// A candidate's total difficulty is not its parent's plus its own
+    /// difficulty.
+    TotalDifficultyMismatch {
+        hash: H256,
+        expected: U256,
+        found: U256,
+    },
+    /// The canonical hash at a height is not one of its candidates.
+    CanonicalNotCandidate(BlockNumber),
+    /// The canonical header at a height is not the child of the canonical
+    /// header below it.
+    CanonicalLinkBroken(BlockNumber),
+    /// The best block is not canonical at its height.
+    BestNotCanonical(H256),
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Corruption::MissingBestBlock => write!(f, "best block pointer is missing"),
+            Corruption::MissingFirstBlock => write!(f, "first block is missing"),
+            Corruption::MissingEntry(number) => write!(f, "entry #{} is missing", number),
+            Corruption::MissingHeader(number, hash) => {
+                write!(f, "header {} at #{} is missing", hash, number)
+            }
+            Corruption::HashMismatch(number, hash) => {
+                write!(
+                    f,
+                    "header at #{} is stored under the wrong hash {}",
+                    number, hash
+                )
+            }
+            Corruption::NumberMismatch {
+                hash,
+                expected,
+                found,
+            } => write!(
+                f,
+                "header {} has number {} but is stored at #{}",
+                hash, found, expected
+            ),
+            Corruption::ParentMismatch(number, hash) => write!(
+                f,
+                "parent of {} at #{} differs from its header",
+                hash, number
+            ),
+            Corruption::UnknownParent(number, hash) => {
+                write!(f, "parent of {} at #{} is not stored", hash, number)
+            }
+            Corruption::TotalDifficultyMismatch {
+                hash,
+                expected,
+                found,
+            } => write!(
+                f,
+                "total difficulty of {} is {} but should be {}",
+                hash, found, expected
+            ),
+            Corruption::CanonicalNotCandidate(number) => {
+                write!(f, "canonical hash at #{} is not a known header", number)
+            }
+            Corruption::CanonicalLinkBroken(number) => write!(
+                f,
+                "canonical header at #{} is not a child of the one below",
+                number
+            ),
+            Corruption::BestNotCanonical(hash) => {
+                write!(f, "best block {} is not canonical", hash)
+            }
+        }
+    }
+}
+
+/// Outcome of an integrity check.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IntegrityReport {
+    /// First height which was checked.
+    pub from: BlockNumber,
+    /// Last height which was checked.
+    pub to: BlockNumber,
+    /// Number of headers checked.
+    pub headers: usize,
+    /// Every inconsistency found, in the order they were found.
+    pub corruptions: Vec<Corruption>,
+}
+
+impl IntegrityReport {
+    /// Whether no inconsistency was found.
+    pub fn is_ok(&self) -> bool {
+        self.corruptions.is_empty()
+    }
+}
+
+/// Check the consistency of the chain held by a store.
+pub fn check(store: &dyn HeaderStore) -> IntegrityReport {
+    let mut report = IntegrityReport::default();
+
+    let best_hash = store.best_block();
+    if best_hash.is_none() {
+        report.corruptions.push(Corruption::MissingBestBlock);
+    }
+    let first = store.first_block().and_then(|hash| store.header(&hash));
+    if first.is_none() {
+        report.corruptions.push(Corruption::MissingFirstBlock);
+    }
+    let (best_hash, first) = match (best_hash, first) {
+        (Some(best_hash), Some(first)) => (best_hash, first),
+        _ => return report,
+    };
+
+    // pruned ranges are replaced by CHT roots, so start after the last one.
+    let first_cht = first_prunable_cht(first.number());
+    let mut next_cht = first_cht;
+    while store.cht_root(next_cht).is_some() {
+        next_cht += 1;
+    }
+    let earliest = if next_cht == first_cht {
+        first.number()
+    } else {
+        cht::start_number(next_cht)
+    };
+    let best_number = store.header(&best_hash).map(|header| header.number());
+
+    report.from = earliest;
+    let mut parents: Vec<Candidate> = Vec::new();
+    let mut number = earliest;
+    loop {
+        let entry = match store.entry(number) {
+            Some(entry) => entry,
+            None => {
+                if best_number.is_some_and(|best| number <= best) {
+                    report.corruptions.push(Corruption::MissingEntry(number));
+                }
+                break;
+            }
+        };
+        report.to = number;
+
+        // carry the correct total difficulty forward, so that a single bad
+        // value is not reported again for every descendant.
+        let mut checked = Vec::with_capacity(entry.candidates.len());
+        for candidate in &entry.candidates {
+            report.headers += 1;
+            let mut candidate = candidate.clone();
+            if let Some(total_difficulty) =
+                check_candidate(store, number, &candidate, &parents, &mut report.corruptions)
+            {
+                candidate.total_difficulty = total_difficulty;
+            }
+            checked.push(candidate);
+        }
+
+        let canonical = entry
+            .candidates
+            .iter()
+            .find(|c| c.hash == entry.canonical_hash);
+        match canonical {
+            None => report
+                .corruptions
+                .push(Corruption::CanonicalNotCandidate(number)),
+            // entries above the best block may be left over from a
+            // retracted fork.
+            Some(canonical)
+                if number > earliest && best_number.is_some_and(|best| number <= best) =>
+            {
+                let parent_canonical = store.canonical_hash(number - 1);
+                if parent_canonical != Some(canonical.parent_hash) {
+                    report
+                        .corruptions
+                        .push(Corruption::CanonicalLinkBroken(number));
+                }
+            }
+            Some(_) => {}
+        }
+
+        if Some(number) == best_number && entry.canonical_hash != best_hash {
+            report
+                .corruptions
+                .push(Corruption::BestNotCanonical(best_hash));
+        }
+
+        parents = checked;
+        number += 1;
+    }
+
+    if best_number.is_none() {
+        report
+            .corruptions
+            .push(Corruption::MissingHeader(number, best_hash));
+    }
+
+    report
+}
+
+fn check_candidate(
+    store: &dyn HeaderStore,
+    number: BlockNumber,
+    candidate: &Candidate,
+    parents: &[Candidate],
+    corruptions: &mut Vec<Corruption>,
+) -> Option<U256> {
+    let header = match store.header(&candidate.hash) {
+        Some(header) => header,
+        None => {
+            corruptions.push(Corruption::MissingHeader(number, candidate.hash));
+            return None;
+        }
+    };
+
+    if header.hash() != candidate.hash {
+        corruptions.push(Corruption::HashMismatch(number, candidate.hash));
+    }
+    if header.number() != number {
+        corruptions.push(Corruption::NumberMismatch {
+            hash: candidate.hash,
+            expected: number,
+            found: header.number(),
+        });
+    }
+    if header.parent_hash() != candidate.parent_hash {
+        corruptions.push(Corruption::ParentMismatch(number, candidate.hash));
+    }
+
+    // the parents of the earliest stored headers were pruned, or the chain
+    // started from a checkpoint whose total difficulty is taken on trust.
+    let expected = if number == 0 {
+        header.difficulty()
+    } else if parents.is_empty() {
+        return None;
+    } else {
+        match parents.iter().find(|p| p.hash == candidate.parent_hash) {
+            Some(parent) => parent.total_difficulty.saturating_add(header.difficulty()),
+            None => {
+                corruptions.push(Corruption::UnknownParent(number, candidate.hash));
+                return None;
+            }
+        }
+    };
+    if candidate.total_difficulty != expected {
+        corruptions.push(Corruption::TotalDifficultyMismatch {
+            hash: candidate.hash,
+            expected,
+            found: candidate.total_difficulty,
+        });
+    }
+    Some(expected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, Corruption};
+    use crate::engine::NullEngine;
+    use crate::header::Header;
+    use crate::header_chain::{Candidate, Entry, HeaderChain, Pruning};
+    use crate::header_store::{Batch, FileStore, HeaderStore};
+    use crate::test_helpers::{child, genesis};
+    use ethereum_types::{H256, U256};
+    use std::path::Path;
+    use std::sync::Arc;
+    use tempdir::TempDir;
+
+    /// Write a chain of ten headers with a fork at height 5 and return the
+    /// canonical headers.
+    fn write_chain(path: &Path) -> Vec<Header> {
+        let genesis = genesis();
+        let store = FileStore::open(path).unwrap();
+        let mut chain =
+            HeaderChain::with_store(Arc::new(NullEngine), genesis.encoded(), Box::new(store))
+                .unwrap();
+
+        let mut headers = vec![genesis];
+        for _ in 0..10 {
+            let header = child(headers.last().unwrap(), 100);
+            chain.insert(header.clone()).unwrap();
+            headers.push(header);
+        }
+        chain.insert(child(&headers[4], 50)).unwrap();
+        headers
+    }
+
+    #[test]
+    fn accepts_consistent_chain() {
+        let tempdir = TempDir::new("integrity").unwrap();
+        let path = tempdir.path().join("headers");
+        write_chain(&path);
+
+        let report = check(&FileStore::open(&path).unwrap());
+        assert!(report.is_ok(), "{:?}", report.corruptions);
+        assert_eq!((report.from, report.to), (0, 10));
+        assert_eq!(report.headers, 12);
+    }
+
+    #[test]
+    fn accepts_pruned_chain() {
+        let tempdir = TempDir::new("integrity").unwrap();
+        let path = tempdir.path().join("headers");
+        {
+            let genesis = genesis();
+            let store = FileStore::open(&path).unwrap();
+            let mut chain =
+                HeaderChain::with_store(Arc::new(NullEngine), genesis.encoded(), Box::new(store))
+                    .unwrap();
+            chain.set_pruning(Pruning::KeepRecent(10)).unwrap();
+            let mut parent = genesis;
+            for _ in 0..2100 {
+                parent = child(&parent, 100);
+                chain.insert(parent.clone()).unwrap();
+            }
+        }
+
+        let report = check(&FileStore::open(&path).unwrap());
+        assert!(report.is_ok(), "{:?}", report.corruptions);
+        assert_eq!((report.from, report.to), (2049, 2100));
+    }
+
+    #[test]
+    fn reports_corruption() {
+        let tempdir = TempDir::new("integrity").unwrap();
+        let path = tempdir.path().join("headers");
+        let headers = write_chain(&path);
+        let mut store = FileStore::open(&path).unwrap();
+
+        let mut batch = Batch::default();
+        // wrong total difficulty at #3.
+        let mut entry = store.entry(3).unwrap();
+        entry.candidates[0].total_difficulty = U256::from(1);
+        batch.insert_entry(3, entry);
+        // canonical index at #7 points at an unrelated header.
+        let stray = child(&headers[0], 1);
+        let mut entry = store.entry(7).unwrap();
+        entry.candidates.push(Candidate {
+            hash: stray.hash(),
+            parent_hash: *stray.parent_hash(),
+            total_difficulty: U256::from(1001),
+        });
+        entry.canonical_hash = stray.hash();
+        batch.insert_header(stray.hash(), stray.encoded());
+        batch.insert_entry(7, entry);
+        // header at #9 is lost.
+        batch.remove_header(headers[9].hash());
+        store.write(batch).unwrap();
+
+        let report = check(&store);
+        assert_eq!(
+            report.corruptions,
+            vec![
+                Corruption::TotalDifficultyMismatch {
+                    hash: headers[3].hash(),
+                    expected: U256::from(1300),
+                    found: U256::from(1),
+                },
+                Corruption::NumberMismatch {
+                    hash: stray.hash(),
+                    expected: 7,
+                    found: 1,
+                },
+                Corruption::UnknownParent(7, stray.hash()),
+                Corruption::CanonicalLinkBroken(7),
+                Corruption::CanonicalLinkBroken(8),
+                Corruption::MissingHeader(9, headers[9].hash()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_missing_entries() {
+        let tempdir = TempDir::new("integrity").unwrap();
+        let path = tempdir.path().join("headers");
+        let headers = write_chain(&path);
+        let mut store = FileStore::open(&path).unwrap();
+
+        let mut batch = Batch::default();
+        batch.remove_entry(6);
+        store.write(batch).unwrap();
+
+        let report = check(&store);
+        assert_eq!(report.corruptions, vec![Corruption::MissingEntry(6)]);
+        assert_eq!(report.to, 5);
+
+        let mut batch = Batch::default();
+        batch.insert_entry(
+            6,
+            Entry {
+                candidates: vec![],
+                canonical_hash: H256::zero(),
+            },
+        );
+        batch.set_best_block(headers[5].hash());
+        store.write(batch).unwrap();
+        assert_eq!(
+            check(&store).corruptions,
+            vec![Corruption::CanonicalNotCandidate(6)]
+        );
+    }
+}
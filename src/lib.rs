@@ -26,16 +26,31 @@ extern crate parity_codec as codec;
 #[macro_use]
 extern crate parity_codec_derive;
 
+pub mod cht;
+pub mod clock;
+#[cfg(test)]
+mod difficulty_tests;
 pub mod header;
 #[macro_use]
 pub mod views;
 pub mod encoded;
+pub mod engine;
+pub mod fork_choice;
 pub mod header_chain;
+pub mod header_store;
+pub mod import_export;
+pub mod integrity;
+pub mod notify;
+pub mod orphan_pool;
+pub mod verification;
+pub mod verification_queue;
 pub mod ethash_wrapper;
 pub mod error;
 pub mod rpc_log;
 pub mod rpc_receipt;
 mod rpc_bytes;
+#[cfg(test)]
+mod test_helpers;
 
 use jsonrpc_core::{IoHandler, Result};
 use jsonrpc_http_server::ServerBuilder;
@@ -0,0 +1,117 @@
+// Copyright 2019 Chainpool
+
+//! Notifications about changes to the header chain.
+//!
+//! Subscribers implement `ChainNotify` and are registered with
+//! `HeaderChain::add_notify`. Consumers which would rather receive events on
+//! another thread can use `ChannelNotify`, which forwards every callback as a
+//! `ChainEvent` over a channel.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+use ethereum_types::H256;
+
+/// Something which is interested in changes to the canonical chain.
+///
+/// When the best block changes, `retracted` is called before `enacted`.
+/// All methods have empty default implementations.
+pub trait ChainNotify: Send + Sync {
+    /// Headers which became canonical, ordered parents first.
+    fn enacted(&self, _headers: &[H256]) {}
+
+    /// Headers which are no longer canonical, ordered children first.
+    fn retracted(&self, _headers: &[H256]) {}
+
+    /// A header was finalized. Every finalized header is reported once,
+    /// parents first.
+    fn finalized(&self, _header: H256) {}
+}
+
+/// A chain event, as delivered by `ChannelNotify`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ChainEvent {
+    /// Headers which became canonical, ordered parents first.
+    Enacted(Vec<H256>),
+    /// Headers which are no longer canonical, ordered children first.
+    Retracted(Vec<H256>),
+    /// A newly finalized header.
+    Finalized(H256),
+}
+
+/// Subscriber which forwards every notification over a channel.
+///
+/// Events are dropped silently once the receiving end hangs up.
+pub struct ChannelNotify {
+    sender: Mutex<Sender<ChainEvent>>,
+}
+
+impl ChannelNotify {
+    /// Create a subscriber along with the receiving end of its channel.
+    pub fn new() -> (Self, Receiver<ChainEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        let notify = ChannelNotify {
+            sender: Mutex::new(sender),
+        };
+        (notify, receiver)
+    }
+
+    fn send(&self, event: ChainEvent) {
+        let _ = self
+            .sender
+            .lock()
+            .expect("sending never panics while the lock is held; qed")
+            .send(event);
+    }
+}
+
+impl ChainNotify for ChannelNotify {
+    fn enacted(&self, headers: &[H256]) {
+        self.send(ChainEvent::Enacted(headers.to_vec()));
+    }
+
+    fn retracted(&self, headers: &[H256]) {
+        self.send(ChainEvent::Retracted(headers.to_vec()));
+    }
+
+    fn finalized(&self, header: H256) {
+        self.send(ChainEvent::Finalized(header));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChainEvent, ChainNotify, ChannelNotify};
+    use ethereum_types::H256;
+    use std::thread;
+
+    #[test]
+    fn forwards_events_over_channel() {
+        let (notify, receiver) = ChannelNotify::new();
+        let hash = H256::from_slice(&[1; 32]);
+
+        let handle = thread::spawn(move || {
+            notify.retracted(&[hash]);
+            notify.enacted(&[hash, hash]);
+            notify.finalized(hash);
+        });
+        handle.join().unwrap();
+
+        let events: Vec<_> = receiver.iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                ChainEvent::Retracted(vec![hash]),
+                ChainEvent::Enacted(vec![hash, hash]),
+                ChainEvent::Finalized(hash),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_closed_channel() {
+        let (notify, receiver) = ChannelNotify::new();
+        drop(receiver);
+        notify.enacted(&[H256::zero()]);
+    }
+}
@@ -0,0 +1,251 @@
+// Copyright 2019 Chainpool
+
+//! Bounded pool of headers whose parent is not yet known.
+//!
+//! Headers are indexed by the hash of their missing parent so that all of
+//! them can be handed back as soon as the parent is imported. Every sender
+//! may only hold a limited number of orphans, and the pool as a whole is
+//! bounded in memory by evicting the oldest orphans first.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+
+use crate::header::Header;
+use ethereum_types::H256;
+use heapsize::HeapSizeOf;
+
+/// Identifier of whoever delivered a header, e.g. a relayer's peer id.
+pub type SenderId = usize;
+
+/// Default maximum number of orphans held per sender.
+pub const DEFAULT_MAX_PER_SENDER: usize = 256;
+
+/// Default maximum memory used by all orphans, in bytes.
+pub const DEFAULT_MAX_MEMORY: usize = 4 * 1024 * 1024;
+
+struct Orphan {
+    header: Header,
+    sender: SenderId,
+    size: usize,
+}
+
+/// Headers with unknown parents, waiting for the parent to be imported.
+pub struct OrphanPool {
+    /// Orphans by the hash of their missing parent.
+    by_parent: HashMap<H256, Vec<Orphan>>,
+    /// Hash and parent hash of every orphan, oldest first.
+    order: VecDeque<(H256, H256)>,
+    hashes: HashSet<H256>,
+    per_sender: HashMap<SenderId, usize>,
+    mem_used: usize,
+    max_per_sender: usize,
+    max_memory: usize,
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        OrphanPool::new(DEFAULT_MAX_PER_SENDER, DEFAULT_MAX_MEMORY)
+    }
+}
+
+impl OrphanPool {
+    /// Create an empty pool with the given limits.
+    pub fn new(max_per_sender: usize, max_memory: usize) -> Self {
+        OrphanPool {
+            by_parent: HashMap::new(),
+            order: VecDeque::new(),
+            hashes: HashSet::new(),
+            per_sender: HashMap::new(),
+            mem_used: 0,
+            max_per_sender,
+            max_memory,
+        }
+    }
+
+    /// Add an orphan delivered by `sender`.
+    ///
+    /// Returns `false` if the header was rejected: because it is already in
+    /// the pool, the sender holds too many orphans, or it alone exceeds the
+    /// memory limit. Older orphans are evicted to make room otherwise.
+    pub fn insert(&mut self, header: Header, sender: SenderId) -> bool {
+        let hash = header.hash();
+        let size = mem::size_of::<Header>() + header.heap_size_of_children();
+
+        if self.hashes.contains(&hash)
+            || size > self.max_memory
+            || self.per_sender.get(&sender).cloned().unwrap_or(0) >= self.max_per_sender
+        {
+            return false;
+        }
+
+        while self.mem_used + size > self.max_memory {
+            let (oldest, parent_hash) = self
+                .order
+                .pop_front()
+                .expect("memory is only used by queued orphans; qed");
+            self.remove(&oldest, &parent_hash);
+        }
+
+        let parent_hash = *header.parent_hash();
+        self.order.push_back((hash, parent_hash));
+        self.hashes.insert(hash);
+        *self.per_sender.entry(sender).or_insert(0) += 1;
+        self.mem_used += size;
+        self.by_parent.entry(parent_hash).or_default().push(Orphan {
+            header,
+            sender,
+            size,
+        });
+        true
+    }
+
+    /// Remove and return all orphans whose parent is `parent_hash`, in the
+    /// order they were added.
+    pub fn take_children(&mut self, parent_hash: &H256) -> Vec<(Header, SenderId)> {
+        let children = match self.by_parent.remove(parent_hash) {
+            Some(children) => children,
+            None => return Vec::new(),
+        };
+
+        let taken: HashSet<H256> = children.iter().map(|orphan| orphan.header.hash()).collect();
+        self.order.retain(|(hash, _)| !taken.contains(hash));
+
+        children
+            .into_iter()
+            .map(|orphan| {
+                self.forget(&orphan);
+                (orphan.header, orphan.sender)
+            })
+            .collect()
+    }
+
+    /// Whether a header with the given hash is in the pool.
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    /// Number of orphans in the pool.
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    /// Whether the pool is empty.
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+
+    /// Number of orphans in the pool delivered by `sender`.
+    pub fn sender_count(&self, sender: SenderId) -> usize {
+        self.per_sender.get(&sender).cloned().unwrap_or(0)
+    }
+
+    /// Approximate memory used by all orphans, in bytes.
+    pub fn mem_used(&self) -> usize {
+        self.mem_used
+    }
+
+    fn remove(&mut self, hash: &H256, parent_hash: &H256) {
+        let orphan = {
+            let siblings = self
+                .by_parent
+                .get_mut(parent_hash)
+                .expect("every queued orphan is indexed by its parent; qed");
+            let index = siblings
+                .iter()
+                .position(|orphan| orphan.header.hash() == *hash)
+                .expect("every queued orphan is indexed by its parent; qed");
+            siblings.remove(index)
+        };
+        if self.by_parent[parent_hash].is_empty() {
+            self.by_parent.remove(parent_hash);
+        }
+        self.forget(&orphan);
+    }
+
+    fn forget(&mut self, orphan: &Orphan) {
+        self.hashes.remove(&orphan.header.hash());
+        self.mem_used -= orphan.size;
+        let count = self
+            .per_sender
+            .get_mut(&orphan.sender)
+            .expect("every queued orphan is counted for its sender; qed");
+        *count -= 1;
+        if *count == 0 {
+            self.per_sender.remove(&orphan.sender);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OrphanPool;
+    use crate::header::Header;
+    use ethereum_types::{H256, U256};
+    use std::mem;
+
+    fn orphan(parent_hash: H256, number: u64) -> Header {
+        let mut header = Header::new();
+        header.set_parent_hash(parent_hash);
+        header.set_number(number);
+        header.set_difficulty(U256::from(100));
+        header
+    }
+
+    #[test]
+    fn takes_children_of_parent() {
+        let mut pool = OrphanPool::default();
+        let parent = H256::from_slice(&[1; 32]);
+        let a = orphan(parent, 5);
+        let b = orphan(parent, 6);
+        let other = orphan(H256::from_slice(&[2; 32]), 5);
+
+        assert!(pool.insert(a.clone(), 1));
+        assert!(pool.insert(b.clone(), 2));
+        assert!(pool.insert(other.clone(), 1));
+        assert!(!pool.insert(a.clone(), 3));
+        assert_eq!(pool.len(), 3);
+
+        let children = pool.take_children(&parent);
+        assert_eq!(children, vec![(a, 1), (b, 2)]);
+        assert_eq!(pool.len(), 1);
+        assert!(pool.contains(&other.hash()));
+        assert_eq!(pool.sender_count(1), 1);
+        assert_eq!(pool.sender_count(2), 0);
+        assert!(pool.take_children(&parent).is_empty());
+    }
+
+    #[test]
+    fn limits_orphans_per_sender() {
+        let mut pool = OrphanPool::new(2, usize::MAX);
+        let parent = H256::from_slice(&[1; 32]);
+
+        assert!(pool.insert(orphan(parent, 1), 1));
+        assert!(pool.insert(orphan(parent, 2), 1));
+        assert!(!pool.insert(orphan(parent, 3), 1));
+        assert!(pool.insert(orphan(parent, 3), 2));
+        assert_eq!(pool.sender_count(1), 2);
+    }
+
+    #[test]
+    fn evicts_oldest_when_memory_is_full() {
+        let size = mem::size_of::<Header>();
+        let mut pool = OrphanPool::new(usize::MAX, 2 * size);
+        let first = orphan(H256::from_slice(&[1; 32]), 1);
+        let second = orphan(H256::from_slice(&[2; 32]), 2);
+        let third = orphan(H256::from_slice(&[3; 32]), 3);
+
+        assert!(pool.insert(first.clone(), 1));
+        assert!(pool.insert(second.clone(), 1));
+        assert!(pool.insert(third.clone(), 1));
+
+        assert!(!pool.contains(&first.hash()));
+        assert!(pool.contains(&second.hash()));
+        assert!(pool.contains(&third.hash()));
+        assert_eq!(pool.mem_used(), 2 * size);
+        assert_eq!(pool.sender_count(1), 2);
+
+        let mut huge = orphan(H256::zero(), 4);
+        huge.set_extra_data(vec![0; 3 * size]);
+        assert!(!pool.insert(huge, 1));
+    }
+}
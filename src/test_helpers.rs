@@ -0,0 +1,23 @@
+// Copyright 2019 Chainpool
+
+//! Header builders shared by the unit tests.
+
+use crate::header::Header;
+use ethereum_types::U256;
+
+/// A genesis header with difficulty 1000.
+pub fn genesis() -> Header {
+    let mut header = Header::new();
+    header.set_difficulty(U256::from(1000));
+    header
+}
+
+/// A child of `parent`, ten seconds later, with the given difficulty.
+pub fn child(parent: &Header, difficulty: u64) -> Header {
+    let mut header = Header::new();
+    header.set_parent_hash(parent.hash());
+    header.set_number(parent.number() + 1);
+    header.set_timestamp(parent.timestamp() + 10);
+    header.set_difficulty(U256::from(difficulty));
+    header
+}
@@ -0,0 +1,322 @@
+// Copyright 2019 Chainpool
+
+//! Verification of full blocks against the header chain.
+//!
+//! The header chain only holds headers, so block bodies are verified on
+//! request: `verify_block` decodes an RLP-encoded block, runs every engine
+//! stage on its header and checks its uncles against the Ethereum uncle rules.
+//! Blocks come from untrusted peers, so malformed RLP is an error rather than
+//! a panic.
+//!
+//! Bodies of earlier blocks are not stored, so an uncle which was already
+//! included by one of the block's ancestors cannot be detected here.
+
+use std::collections::HashSet;
+
+use crate::engine::Engine;
+use crate::error::{BlockError, Error};
+use crate::header::Header;
+use crate::header_chain::HeaderChain;
+use ethereum_types::H256;
+use hash::keccak;
+use rlp::{DecoderError, Rlp};
+use unexpected::{Mismatch, OutOfBounds};
+
+/// Maximum number of generations an uncle may be behind the block
+/// including it.
+pub const MAX_UNCLE_AGE: u64 = 6;
+
+/// Verify an RLP-encoded full block whose parent is in the chain.
+pub fn verify_block<E: Engine>(chain: &HeaderChain<E>, block: &[u8]) -> Result<(), Error> {
+    let block = Rlp::new(block);
+    if block.item_count()? != 3 {
+        return Err(DecoderError::RlpIncorrectListLen.into());
+    }
+    let header: Header = block.val_at(0)?;
+    // transactions aren't verified here, but must still form a list.
+    block.at(1)?.item_count()?;
+    let uncles_rlp = block.at(2)?;
+    let uncles: Vec<Header> = uncles_rlp.as_list()?;
+
+    let parent = load_header(chain, header.parent_hash())
+        .ok_or_else(|| BlockError::UnknownParent(*header.parent_hash()))??;
+    chain.engine().verify_block_full(&header, &parent)?;
+
+    let expected = keccak(uncles_rlp.as_raw());
+    if &expected != header.uncles_hash() {
+        return Err(From::from(BlockError::InvalidUnclesHash(Mismatch {
+            expected,
+            found: *header.uncles_hash(),
+        })));
+    }
+
+    verify_uncles(chain, &header, &uncles)
+}
+
+fn verify_uncles<E: Engine>(
+    chain: &HeaderChain<E>,
+    header: &Header,
+    uncles: &[Header],
+) -> Result<(), Error> {
+    if uncles.is_empty() {
+        return Ok(());
+    }
+
+    let max_uncles = chain.engine().maximum_uncle_count(header.number());
+    if uncles.len() > max_uncles {
+        return Err(From::from(BlockError::TooManyUncles(OutOfBounds {
+            min: None,
+            max: Some(max_uncles),
+            found: uncles.len(),
+        })));
+    }
+
+    // the block and its recent ancestors can't be uncles.
+    let mut excluded = HashSet::new();
+    excluded.insert(header.hash());
+    let mut hash = *header.parent_hash();
+    excluded.insert(hash);
+    for _ in 0..MAX_UNCLE_AGE {
+        match chain.block_header(&hash) {
+            Some(ancestor) => {
+                hash = ancestor.parent_hash();
+                excluded.insert(hash);
+            }
+            None => break,
+        }
+    }
+
+    let mut verified = HashSet::new();
+    for uncle in uncles {
+        let uncle_hash = uncle.hash();
+        if excluded.contains(&uncle_hash) {
+            return Err(From::from(BlockError::UncleInChain(uncle_hash)));
+        }
+        if verified.contains(&uncle_hash) {
+            return Err(From::from(BlockError::DuplicateUncle(uncle_hash)));
+        }
+
+        let depth = header.number().saturating_sub(uncle.number());
+        if depth > MAX_UNCLE_AGE {
+            return Err(From::from(BlockError::UncleTooOld(OutOfBounds {
+                min: Some(header.number() - MAX_UNCLE_AGE),
+                max: Some(header.number() - 1),
+                found: uncle.number(),
+            })));
+        } else if depth < 1 {
+            return Err(From::from(BlockError::UncleIsBrother(OutOfBounds {
+                min: Some(header.number().saturating_sub(MAX_UNCLE_AGE)),
+                max: Some(header.number().saturating_sub(1)),
+                found: uncle.number(),
+            })));
+        }
+
+        // the uncle's parent must be the block's ancestor `depth` generations
+        // back, i.e. the uncle forks off the chain right there.
+        let uncle_parent = load_header(chain, uncle.parent_hash())
+            .ok_or_else(|| BlockError::UnknownUncleParent(*uncle.parent_hash()))??;
+        let mut expected_uncle_parent = *header.parent_hash();
+        for _ in 0..depth {
+            match chain.block_header(&expected_uncle_parent) {
+                Some(ancestor) => expected_uncle_parent = ancestor.parent_hash(),
+                None => break,
+            }
+        }
+        if expected_uncle_parent != *uncle.parent_hash() {
+            return Err(From::from(BlockError::UncleParentNotInChain(
+                *uncle.parent_hash(),
+            )));
+        }
+
+        if uncle.number() != uncle_parent.number() + 1 {
+            return Err(From::from(BlockError::InvalidNumber(Mismatch {
+                expected: uncle_parent.number() + 1,
+                found: uncle.number(),
+            })));
+        }
+        chain.engine().verify_block_full(uncle, &uncle_parent)?;
+        verified.insert(uncle_hash);
+    }
+
+    Ok(())
+}
+
+fn load_header<E: Engine>(chain: &HeaderChain<E>, hash: &H256) -> Option<Result<Header, Error>> {
+    chain
+        .block_header(hash)
+        .map(|header| header.decode().map_err(Into::into))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::verify_block;
+    use crate::engine::NullEngine;
+    use crate::error::{BlockError, Error, ErrorKind};
+    use crate::header::Header;
+    use crate::header_chain::HeaderChain;
+    use crate::test_helpers::{child, genesis};
+    use ethereum_types::H256;
+    use hash::keccak;
+    use rlp::RlpStream;
+    use std::sync::Arc;
+
+    /// A chain of ten blocks with a side fork of two blocks off block 8.
+    fn chain() -> (HeaderChain<NullEngine>, Vec<Header>, Vec<Header>) {
+        let genesis = genesis();
+        let mut chain = HeaderChain::new(Arc::new(NullEngine), genesis.encoded());
+
+        let mut headers = vec![genesis];
+        for _ in 0..10 {
+            let header = child(headers.last().unwrap(), 100);
+            chain.insert(header.clone()).unwrap();
+            headers.push(header);
+        }
+        let mut fork = vec![child(&headers[8], 50)];
+        fork.push(child(&fork[0], 50));
+        for header in &fork {
+            chain.insert(header.clone()).unwrap();
+        }
+        (chain, headers, fork)
+    }
+
+    /// Encode a child of `parent` including `uncles`.
+    fn block(parent: &Header, uncles: &[Header]) -> Vec<u8> {
+        let mut header = child(parent, 100);
+        header.set_uncles_hash(keccak(rlp::encode_list(uncles)));
+        encode_block(&header, uncles)
+    }
+
+    fn encode_block(header: &Header, uncles: &[Header]) -> Vec<u8> {
+        let mut stream = RlpStream::new_list(3);
+        stream.append(header);
+        stream.begin_list(0);
+        stream.append_list(uncles);
+        stream.out()
+    }
+
+    fn block_error(result: Result<(), Error>) -> BlockError {
+        match result {
+            Err(Error(ErrorKind::Block(err), _)) => err,
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_valid_uncles() {
+        let (chain, headers, fork) = chain();
+        let best = &headers[10];
+
+        assert!(verify_block(&chain, &block(best, &[])).is_ok());
+        // two and six generations back.
+        let uncles = [fork[0].clone(), child(&headers[4], 99)];
+        assert!(verify_block(&chain, &block(best, &uncles)).is_ok());
+    }
+
+    #[test]
+    fn checks_uncles_hash() {
+        let (chain, headers, fork) = chain();
+        let header = child(&headers[10], 100);
+        let block = encode_block(&header, &fork[..1]);
+
+        match block_error(verify_block(&chain, &block)) {
+            BlockError::InvalidUnclesHash(mis) => {
+                assert_eq!(mis.expected, keccak(rlp::encode_list(&fork[..1])));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_uncles() {
+        let (chain, headers, fork) = chain();
+        let best = &headers[10];
+
+        let too_many = [fork[0].clone(), fork[1].clone(), child(&headers[7], 99)];
+        match block_error(verify_block(&chain, &block(best, &too_many))) {
+            BlockError::TooManyUncles(oob) => assert_eq!(oob.max, Some(2)),
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let too_old = child(&headers[3], 99);
+        match block_error(verify_block(&chain, &block(best, &[too_old]))) {
+            BlockError::UncleTooOld(oob) => assert_eq!(oob.found, 4),
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        let brother = child(&headers[10], 99);
+        match block_error(verify_block(&chain, &block(best, &[brother]))) {
+            BlockError::UncleIsBrother(oob) => assert_eq!(oob.found, 11),
+            other => panic!("unexpected error: {:?}", other),
+        }
+
+        assert_eq!(
+            block_error(verify_block(&chain, &block(best, &headers[9..10]))),
+            BlockError::UncleInChain(headers[9].hash())
+        );
+
+        let uncle = fork[0].clone();
+        assert_eq!(
+            block_error(verify_block(
+                &chain,
+                &block(best, &[uncle.clone(), uncle.clone()])
+            )),
+            BlockError::DuplicateUncle(uncle.hash())
+        );
+
+        // the parent of fork[1] is known, but not an ancestor of the block.
+        assert_eq!(
+            block_error(verify_block(&chain, &block(best, &fork[1..]))),
+            BlockError::UncleParentNotInChain(fork[0].hash())
+        );
+
+        let mut orphan = child(&headers[8], 99);
+        orphan.set_parent_hash(H256::from_slice(&[1; 32]));
+        assert_eq!(
+            block_error(verify_block(&chain, &block(best, &[orphan]))),
+            BlockError::UnknownUncleParent(H256::from_slice(&[1; 32]))
+        );
+    }
+
+    #[test]
+    fn rejects_block_with_unknown_parent() {
+        let (chain, _, _) = chain();
+        let mut stranger = Header::new();
+        stranger.set_number(20);
+
+        assert_eq!(
+            block_error(verify_block(&chain, &block(&stranger, &[]))),
+            BlockError::UnknownParent(stranger.hash())
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_blocks() {
+        let (chain, headers, _) = chain();
+        let valid = block(&headers[10], &[]);
+        assert!(verify_block(&chain, &valid).is_ok());
+
+        let truncated = &valid[..valid.len() / 2];
+        let garbage = [0xff; 8];
+        let two_items = rlp::encode_list(&headers[..2]);
+        let uncles_not_headers = {
+            let mut stream = RlpStream::new_list(3);
+            stream.append(&headers[10]);
+            stream.begin_list(0);
+            stream.append(&headers[9]);
+            stream.out()
+        };
+        for bytes in [
+            truncated,
+            &garbage[..],
+            &two_items[..],
+            &uncles_not_headers[..],
+        ]
+        .iter()
+        {
+            match verify_block(&chain, bytes) {
+                Err(Error(ErrorKind::Decoder(_), _)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
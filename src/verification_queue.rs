@@ -0,0 +1,397 @@
+// Copyright 2019 Chainpool
+
+//! Queue which verifies header seals on a pool of worker threads.
+//!
+//! Seal verification (in particular computing the Ethash light cache) is by
+//! far the most expensive part of importing a header and does not depend on
+//! the chain, so it is done in parallel. Verified headers are handed back in
+//! the order they were queued, ready to be inserted into the header chain.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+use crate::error::{Error, ErrorKind};
+use crate::header::Header;
+use ethereum_types::H256;
+use heapsize::HeapSizeOf;
+use types::verification_queue_info::VerificationQueueInfo;
+
+/// Verifies a header on its own, without access to its parent or the chain.
+pub trait Verifier: Send + Sync {
+    /// Check the header's seal.
+    fn verify(&self, header: &Header) -> Result<(), Error>;
+}
+
+impl<F> Verifier for F
+where
+    F: Fn(&Header) -> Result<(), Error> + Send + Sync,
+{
+    fn verify(&self, header: &Header) -> Result<(), Error> {
+        self(header)
+    }
+}
+
+/// Verification queue configuration.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Maximum number of headers in the queue.
+    pub max_queue_size: usize,
+    /// Maximum memory used by queued headers, in bytes.
+    pub max_mem_use: usize,
+    /// Number of worker threads.
+    pub num_verifiers: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_queue_size: 30_000,
+            max_mem_use: 50 * 1024 * 1024,
+            num_verifiers: thread::available_parallelism().map_or(1, |n| n.get()),
+        }
+    }
+}
+
+struct Item {
+    seq: u64,
+    hash: H256,
+    header: Header,
+    size: usize,
+}
+
+#[derive(Default)]
+struct State {
+    next_seq: u64,
+    /// Sequence number of the next header to be drained.
+    next_drain: u64,
+    unverified: VecDeque<Item>,
+    verifying: usize,
+    verified: BTreeMap<u64, (Item, Result<(), Error>)>,
+    hashes: HashSet<H256>,
+    mem_used: usize,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when a header is queued or on shutdown.
+    more_to_verify: Condvar,
+    /// Signalled when a header finished verification.
+    progress: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .expect("verifiers never panic while holding the lock; qed")
+    }
+}
+
+/// Header verification queue.
+pub struct VerificationQueue {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    config: Config,
+}
+
+impl VerificationQueue {
+    /// Create a queue and start its worker threads. Fails if the
+    /// configuration asks for no worker threads, as nothing would ever be
+    /// verified.
+    pub fn new(config: Config, verifier: Arc<dyn Verifier>) -> Result<Self, Error> {
+        if config.num_verifiers == 0 {
+            return Err(ErrorKind::NoVerifiers.into());
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            more_to_verify: Condvar::new(),
+            progress: Condvar::new(),
+        });
+
+        let workers = (0..config.num_verifiers)
+            .map(|i| {
+                let shared = shared.clone();
+                let verifier = verifier.clone();
+                thread::Builder::new()
+                    .name(format!("Verifier #{}", i))
+                    .spawn(move || verify_loop(&shared, &*verifier))
+                    .expect("failed to spawn verifier thread")
+            })
+            .collect();
+
+        Ok(VerificationQueue {
+            shared,
+            workers,
+            config,
+        })
+    }
+
+    /// Add a header to the queue, returning its hash.
+    pub fn import(&self, header: Header) -> Result<H256, Error> {
+        let hash = header.hash();
+        let size = mem::size_of::<Header>() + header.heap_size_of_children();
+
+        let mut state = self.shared.lock();
+        if state.hashes.contains(&hash) {
+            return Err(ErrorKind::AlreadyQueued.into());
+        }
+        let queued = state.hashes.len();
+        if queued >= self.config.max_queue_size || state.mem_used + size > self.config.max_mem_use {
+            return Err(ErrorKind::QueueFull(queued).into());
+        }
+
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.hashes.insert(hash);
+        state.mem_used += size;
+        state.unverified.push_back(Item {
+            seq,
+            hash,
+            header,
+            size,
+        });
+        self.shared.more_to_verify.notify_one();
+        Ok(hash)
+    }
+
+    /// Take the headers whose verification has finished, in the order they
+    /// were queued. Stops at the first header still being verified.
+    pub fn drain(&self) -> Vec<(H256, Result<Header, Error>)> {
+        let mut state = self.shared.lock();
+        let mut drained = Vec::new();
+
+        loop {
+            let next = state.next_drain;
+            let (item, result) = match state.verified.remove(&next) {
+                Some(verified) => verified,
+                None => break,
+            };
+            state.next_drain += 1;
+            state.hashes.remove(&item.hash);
+            state.mem_used -= item.size;
+            drained.push((item.hash, result.map(|_| item.header)));
+        }
+
+        drained
+    }
+
+    /// Block until every queued header has been verified.
+    pub fn flush(&self) {
+        let mut state = self.shared.lock();
+        while !state.unverified.is_empty() || state.verifying > 0 {
+            state = self
+                .shared
+                .progress
+                .wait(state)
+                .expect("verifiers never panic while holding the lock; qed");
+        }
+    }
+
+    /// Get the current state of the queue.
+    pub fn queue_info(&self) -> VerificationQueueInfo {
+        let state = self.shared.lock();
+        VerificationQueueInfo {
+            unverified_queue_size: state.unverified.len(),
+            verifying_queue_size: state.verifying,
+            verified_queue_size: state.verified.len(),
+            max_queue_size: self.config.max_queue_size,
+            max_mem_use: self.config.max_mem_use,
+            mem_used: state.mem_used,
+        }
+    }
+}
+
+impl Drop for VerificationQueue {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.more_to_verify.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn verify_loop(shared: &Shared, verifier: &dyn Verifier) {
+    loop {
+        let item = {
+            let mut state = shared.lock();
+            loop {
+                if state.shutdown {
+                    return;
+                }
+                if let Some(item) = state.unverified.pop_front() {
+                    state.verifying += 1;
+                    break item;
+                }
+                state = shared
+                    .more_to_verify
+                    .wait(state)
+                    .expect("verifiers never panic while holding the lock; qed");
+            }
+        };
+
+        // a panicking verifier must not take the worker down with it, or the
+        // header would count as being verified forever and `flush` would
+        // never return.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| verifier.verify(&item.header)))
+            .unwrap_or_else(|_| Err(ErrorKind::VerifierPanicked.into()));
+
+        let mut state = shared.lock();
+        state.verifying -= 1;
+        state.verified.insert(item.seq, (item, result));
+        shared.progress.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, VerificationQueue};
+    use crate::error::{Error, ErrorKind};
+    use crate::header::Header;
+    use ethereum_types::U256;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn header(number: u64) -> Header {
+        let mut header = Header::new();
+        header.set_number(number);
+        header.set_difficulty(U256::from(100));
+        header
+    }
+
+    fn config(num_verifiers: usize) -> Config {
+        Config {
+            max_queue_size: 100,
+            max_mem_use: usize::MAX,
+            num_verifiers,
+        }
+    }
+
+    #[test]
+    fn drains_in_queue_order() {
+        let verifier = |header: &Header| -> Result<(), Error> {
+            // later headers finish first.
+            thread::sleep(Duration::from_millis(20 - header.number()));
+            if header.number() == 5 {
+                Err(ErrorKind::PowInvalid.into())
+            } else {
+                Ok(())
+            }
+        };
+        let queue = VerificationQueue::new(config(4), Arc::new(verifier)).unwrap();
+
+        let hashes: Vec<_> = (0..20).map(|n| queue.import(header(n)).unwrap()).collect();
+        queue.flush();
+
+        let drained = queue.drain();
+        assert_eq!(drained.len(), 20);
+        for (number, (hash, result)) in drained.into_iter().enumerate() {
+            assert_eq!(hash, hashes[number]);
+            assert_eq!(result.is_ok(), number != 5);
+        }
+        assert!(queue.queue_info().is_empty());
+        assert_eq!(queue.queue_info().mem_used, 0);
+    }
+
+    #[test]
+    fn reports_queue_info() {
+        let release = Arc::new(AtomicBool::new(false));
+        let verifier = {
+            let release = release.clone();
+            move |_: &Header| -> Result<(), Error> {
+                while !release.load(Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                Ok(())
+            }
+        };
+        let queue = VerificationQueue::new(config(1), Arc::new(verifier)).unwrap();
+
+        for number in 0..3 {
+            queue.import(header(number)).unwrap();
+        }
+        match queue.import(header(0)) {
+            Err(Error(ErrorKind::AlreadyQueued, _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        while queue.queue_info().verifying_queue_size == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let info = queue.queue_info();
+        assert_eq!(info.unverified_queue_size, 2);
+        assert_eq!(info.verifying_queue_size, 1);
+        assert_eq!(info.verified_queue_size, 0);
+        assert!(info.mem_used > 0);
+        assert!(queue.drain().is_empty());
+
+        release.store(true, Ordering::SeqCst);
+        queue.flush();
+        assert_eq!(queue.queue_info().verified_queue_size, 3);
+        assert_eq!(queue.drain().len(), 3);
+    }
+
+    #[test]
+    fn rejects_when_full() {
+        let mut config = config(1);
+        config.max_queue_size = 2;
+        let queue = VerificationQueue::new(
+            config,
+            Arc::new(|_: &Header| -> Result<(), Error> { Ok(()) }),
+        )
+        .unwrap();
+
+        queue.import(header(0)).unwrap();
+        queue.import(header(1)).unwrap();
+        match queue.import(header(2)) {
+            Err(Error(ErrorKind::QueueFull(2), _)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        queue.flush();
+        queue.drain();
+        assert!(queue.import(header(2)).is_ok());
+    }
+
+    #[test]
+    fn survives_panicking_verifier() {
+        let verifier = |header: &Header| -> Result<(), Error> {
+            if header.number() == 1 {
+                panic!("verifier bug");
+            }
+            Ok(())
+        };
+        let queue = VerificationQueue::new(config(1), Arc::new(verifier)).unwrap();
+
+        for number in 0..3 {
+            queue.import(header(number)).unwrap();
+        }
+        queue.flush();
+
+        let drained = queue.drain();
+        assert_eq!(drained.len(), 3);
+        match drained[1].1 {
+            Err(Error(ErrorKind::VerifierPanicked, _)) => {}
+            ref other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(drained[0].1.is_ok() && drained[2].1.is_ok());
+        assert!(queue.queue_info().is_empty());
+    }
+
+    #[test]
+    fn rejects_zero_verifiers() {
+        let verifier = |_: &Header| -> Result<(), Error> { Ok(()) };
+        match VerificationQueue::new(config(0), Arc::new(verifier)) {
+            Err(Error(ErrorKind::NoVerifiers, _)) => {}
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("queue without verifiers was created"),
+        }
+    }
+}
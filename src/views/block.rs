@@ -19,10 +19,8 @@
 use bytes::Bytes;
 use ethereum_types::H256;
 use hash::keccak;
-use header::Header;
-use transaction::{UnverifiedTransaction, LocalizedTransaction};
-use views::{TransactionView, HeaderView};
-use super::ViewRlp;
+use crate::header::Header;
+use super::{HeaderView, ViewRlp};
 
 /// View onto block rlp.
 pub struct BlockView<'a> {
@@ -32,20 +30,6 @@ pub struct BlockView<'a> {
 impl<'a> BlockView<'a> {
 	/// Creates new view onto block from rlp.
 	/// Use the `view!` macro to create this view in order to capture debugging info.
-	///
-	/// # Example
-	///
-	/// ```
-	/// #[macro_use]
-	/// extern crate ethcore;
-	///
-	/// use ethcore::views::{BlockView};
-	///
-	/// fn main() {
-	/// let bytes : &[u8] = &[];
-	/// let block_view = view!(BlockView, bytes);
-	/// }
-	/// ```
 	pub fn new(rlp: ViewRlp<'a>) -> BlockView<'a> {
 		BlockView {
 			rlp: rlp
@@ -77,28 +61,6 @@ impl<'a> BlockView<'a> {
 		HeaderView::new(self.header_rlp())
 	}
 
-	/// Return List of transactions in given block.
-	pub fn transactions(&self) -> Vec<UnverifiedTransaction> {
-		self.rlp.list_at(1)
-	}
-
-	/// Return List of transactions with additional localization info.
-	pub fn localized_transactions(&self) -> Vec<LocalizedTransaction> {
-		let header = self.header_view();
-		let block_hash = header.hash();
-		let block_number = header.number();
-		self.transactions()
-			.into_iter()
-			.enumerate()
-			.map(|(i, t)| LocalizedTransaction {
-				signed: t,
-				block_hash: block_hash.clone(),
-				block_number: block_number,
-				transaction_index: i,
-				cached_sender: None,
-			}).collect()
-	}
-
 	/// Return the raw rlp for the transactions in the given block.
 	pub fn transactions_rlp(&self) -> ViewRlp<'a> {
 		self.rlp.at(1)
@@ -109,35 +71,11 @@ impl<'a> BlockView<'a> {
 		self.transactions_rlp().iter().count()
 	}
 
-	/// Return List of transactions in given block.
-	pub fn transaction_views(&self) -> Vec<TransactionView<'a>> {
-		self.transactions_rlp().iter().map(TransactionView::new).collect()
-	}
-
 	/// Return transaction hashes.
 	pub fn transaction_hashes(&self) -> Vec<H256> {
 		self.transactions_rlp().iter().map(|rlp| keccak(rlp.as_raw())).collect()
 	}
 
-	/// Returns transaction at given index without deserializing unnecessary data.
-	pub fn transaction_at(&self, index: usize) -> Option<UnverifiedTransaction> {
-		self.transactions_rlp().iter().nth(index).map(|rlp| rlp.as_val())
-	}
-
-	/// Returns localized transaction at given index.
-	pub fn localized_transaction_at(&self, index: usize) -> Option<LocalizedTransaction> {
-		let header = self.header_view();
-		let block_hash = header.hash();
-		let block_number = header.number();
-		self.transaction_at(index).map(|t| LocalizedTransaction {
-			signed: t,
-			block_hash: block_hash,
-			block_number: block_number,
-			transaction_index: index,
-			cached_sender: None,
-		})
-	}
-
 	/// Returns raw rlp for the uncles in the given block
 	pub fn uncles_rlp(&self) -> ViewRlp<'a> {
 		self.rlp.at(2)
@@ -18,13 +18,13 @@
 
 #[macro_use]
 mod view_rlp;
-//mod block;
+mod block;
 //mod body;
 mod header;
 //mod transaction;
 
+pub use self::block::BlockView;
 pub use self::view_rlp::ViewRlp;
-//pub use self::block::BlockView;
 //pub use self::body::BodyView;
 pub use self::header::HeaderView;
 //pub use self::transaction::TransactionView;